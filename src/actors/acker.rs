@@ -1,79 +1,402 @@
-use pulsar::{Consumer, Pulsar, SubType, TokioExecutor};
-use tokio::sync::mpsc;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use crate::TestData;
+use pulsar::{message::proto::MessageIdData, DeserializeMessage};
+use tokio::{
+    sync::mpsc,
+    task::{JoinHandle, JoinSet},
+    time::{interval, sleep_until, Instant, MissedTickBehavior},
+};
+use tokio_metrics::TaskMonitor;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
-use super::AckerCommand;
+use crate::{
+    broker::Broker,
+    dead_letter::DeadLetter,
+    error::Error,
+    metrics::Metrics,
+    retry_letter::RetryLetter,
+};
 
-pub struct Acker {
-    pulsar_consumer: Consumer<TestData, TokioExecutor>,
-    acker_rx: mpsc::Receiver<AckerCommand>,
+use super::{
+    AckSender, AckerCommand, ConsumerAck, ExhaustedAction, PipelineConfig, RetryDecision, RetryMode, RetryPolicy,
+};
+
+/// Actor resolving the executor results into acks or nacks
+///
+/// it does not own a consumer, every ack is routed back to the receiver that delivered
+/// the message so it is acked by the same consumer instance
+///
+/// acks are buffered per topic and receiver, then flushed together once `ack_batch_size`
+/// of them are buffered or every `ack_flush_interval`
+///
+/// failed messages go through the `RetryPolicy`: the nack is held until the backoff
+/// elapsed, or the exhausted action is applied instead
+pub struct Acker<T, B> {
+    acker_rx: mpsc::Receiver<AckerCommand<T>>,
+    retry: RetryPolicy,
+    topic_retries: BTreeMap<String, RetryPolicy>,
+    ack_batch_size: usize,
+    ack_flush_interval: Duration,
+    ack_buffers: Vec<AckBuffer>,
+    metrics: Arc<AckerMetrics>,
+    dead_letter: DeadLetter<B>,
+    retry_letter: RetryLetter<B>,
+    // nacks waiting for their backoff, the sequence number keeps keys unique
+    delayed_nacks: BTreeMap<(Instant, u64), (AckSender, ConsumerAck)>,
+    next_sequence: u64,
+    // dead and retry letter publications, the original is acked once its publication succeeded
+    publications: JoinSet<()>,
 }
 
-impl Acker {
-    pub async fn new(
-        pulsar_client: &Pulsar<TokioExecutor>,
-        acker_rx: mpsc::Receiver<AckerCommand>,
-        topics: Vec<String>,
-    ) -> Self {
-        let pulsar_consumer: Consumer<TestData, TokioExecutor> = pulsar_client
-            .consumer()
-            .with_topics(topics)
-            //.with_topic("test")
-            //.with_topic("test-01")
-            .with_consumer_name("test_consumer_acker")
-            .with_subscription_type(SubType::KeyShared)
-            .with_subscription("test_subscription")
-            .build()
-            .await
-            .unwrap(); // to do handle errors
+/// Acks of one topic waiting to be flushed to the receiver that delivered them
+struct AckBuffer {
+    topic: String,
+    ack_tx: AckSender,
+    message_ids: Vec<BufferedAck>,
+}
+
+struct BufferedAck {
+    message_id: MessageIdData,
+    // when the acker received the ack, to measure the ack latency
+    queued_at: Instant,
+    // when the handler returned, handed to the receiver which records the handled to acked latency
+    handled_at: Instant,
+}
+
+/// Counters of the acker, shared with whoever reports them
+#[derive(Debug, Default)]
+pub struct AckerMetrics {
+    /// batches of acks sent to the receivers
+    pub flushes: AtomicU64,
+    /// acks sent in those batches
+    pub acks: AtomicU64,
+    /// time acks waited in the buffers before their flush, summed, in microseconds
+    pub ack_latency_total_us: AtomicU64,
+    /// longest time an ack waited in the buffers, in microseconds
+    pub ack_latency_max_us: AtomicU64,
+}
+
+impl AckerMetrics {
+    pub fn avg_ack_latency(&self) -> Duration {
+        let acks = self.acks.load(Ordering::Relaxed);
+        let total = self.ack_latency_total_us.load(Ordering::Relaxed);
+
+        Duration::from_micros(total.checked_div(acks).unwrap_or(0))
+    }
+
+    pub fn max_ack_latency(&self) -> Duration {
+        Duration::from_micros(self.ack_latency_max_us.load(Ordering::Relaxed))
+    }
+
+    fn record_flush(&self, queued_at: impl Iterator<Item = Instant>, now: Instant) {
+        let flush_latency = Metrics::global().ack_flush_latency.with_label_values(&[]);
+        let mut acks = 0;
+        for queued_at in queued_at {
+            let waited = now.duration_since(queued_at);
+            flush_latency.observe(waited.as_secs_f64());
+            let latency = waited.as_micros() as u64;
+            self.ack_latency_total_us.fetch_add(latency, Ordering::Relaxed);
+            self.ack_latency_max_us.fetch_max(latency, Ordering::Relaxed);
+            acks += 1;
+        }
+
+        self.acks.fetch_add(acks, Ordering::Relaxed);
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        Metrics::global().ack_flushes.inc();
+    }
+}
 
+impl<T: DeserializeMessage + Send + 'static, B: Broker> Acker<T, B> {
+    pub fn new(
+        acker_rx: mpsc::Receiver<AckerCommand<T>>,
+        config: &PipelineConfig,
+        dead_letter: DeadLetter<B>,
+        retry_letter: RetryLetter<B>,
+        metrics: Arc<AckerMetrics>,
+    ) -> Self {
         Self {
-            pulsar_consumer,
             acker_rx,
+            retry: config.retry.clone(),
+            topic_retries: config.topic_retries.clone(),
+            ack_batch_size: config.ack_batch_size.max(1),
+            ack_flush_interval: config.ack_flush_interval.max(Duration::from_millis(1)),
+            ack_buffers: Vec::new(),
+            metrics,
+            dead_letter,
+            retry_letter,
+            delayed_nacks: BTreeMap::new(),
+            next_sequence: 0,
+            publications: JoinSet::new(),
         }
     }
 
+    /// Handle msg acks or nacks every command until all the senders are dropped
+    ///
+    /// a failed ack is logged and does not stop the actor, the broker will redeliver it.
+    /// Nacks still waiting for their backoff are sent right away once the senders are gone,
+    /// the consumers are about to close and would redeliver them anyway
     pub async fn handle_msg(&mut self) {
+        let mut flush_interval = interval(self.ack_flush_interval);
+        flush_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
         loop {
-            while let Some(cmd) = self.acker_rx.recv().await {
-                match cmd {
-                    AckerCommand::Ack { msg } => {
-                        println!(
-                            "ACK TOPIC => {}, message_id => {:?}",
-                            &msg.topic, msg.message_id.id
-                        );
-                        self.pulsar_consumer
-                            .ack_with_id(&msg.topic, msg.message_id.id)
-                            .await
-                            .expect("should ack");
+            let buffered: usize = self.ack_buffers.iter().map(|buffer| buffer.message_ids.len()).sum();
+            Metrics::global()
+                .acker_backlog
+                .set((self.acker_rx.len() + buffered + self.delayed_nacks.len()) as i64);
+
+            let next_deadline = self.delayed_nacks.first_key_value().map(|((deadline, _), _)| *deadline);
+
+            tokio::select! {
+                cmd = self.acker_rx.recv() => match cmd {
+                    Some(cmd) => self.handle_cmd(cmd),
+                    None => break,
+                },
+                _ = flush_interval.tick() => self.flush_all(),
+                _ = sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {
+                    self.send_due_nacks(Instant::now());
+                }
+                Some(result) = self.publications.join_next() => log_task_result(result),
+            }
+        }
+
+        while let Some((_, (ack_tx, nack))) = self.delayed_nacks.pop_first() {
+            send(&ack_tx, nack);
+        }
+
+        while let Some(result) = self.publications.join_next().await {
+            log_task_result(result);
+        }
+
+        self.flush_all();
+        Metrics::global().acker_backlog.set(0);
+
+        info!(
+            acks = self.metrics.acks.load(Ordering::Relaxed),
+            flushes = self.metrics.flushes.load(Ordering::Relaxed),
+            ack_latency_avg = ?self.metrics.avg_ack_latency(),
+            ack_latency_max = ?self.metrics.max_ack_latency(),
+            "acker flushed all pending acks"
+        );
+    }
+
+    fn handle_cmd(&mut self, cmd: AckerCommand<T>) {
+        let span = match &cmd {
+            AckerCommand::Ack { msg, .. } | AckerCommand::Nack { msg, .. } => msg.span().clone(),
+        };
+        let _entered = span.enter();
+        let ack_span = info_span!("ack");
+        let _ack_entered = ack_span.enter();
+
+        match cmd {
+            AckerCommand::Ack { msg, ack_tx, handled_at } => {
+                debug!("ack buffered");
+                self.buffer_ack(msg.topic, msg.message_id, ack_tx, Instant::from_std(handled_at));
+            }
+            AckerCommand::Nack { msg, ack_tx, error } => {
+                let attempt = msg.attempt();
+
+                let retry = self.topic_retries.get(msg.origin_topic()).unwrap_or(&self.retry);
+                match retry.decide(attempt, &error) {
+                    RetryDecision::Retry(delay) if self.retry.mode == RetryMode::RetryLetter => {
+                        let retry_letter = self.retry_letter.clone();
+
+                        let publication = async move {
+                            let topic = retry_letter.topic_for(msg.origin_topic());
+                            // the original is only acked once the retry letter is safely stored
+                            let ack = match retry_letter.publish(&msg, &error, attempt, delay).await {
+                                Ok(()) => {
+                                    debug!(attempt, retry_topic = %topic, ?delay, "retry letter published");
+                                    ConsumerAck::Ack {
+                                        topic: msg.topic,
+                                        message_id: msg.message_id,
+                                    }
+                                }
+                                Err(e) => {
+                                    warn!(retry_topic = %topic, error = %e, "retry letter failed, nacking");
+                                    ConsumerAck::Nack {
+                                        topic: msg.topic,
+                                        message_id: msg.message_id,
+                                    }
+                                }
+                            };
+                            send(&ack_tx, ack);
+                        };
+                        self.publications.spawn(publication.instrument(ack_span.clone()));
                     }
-                    AckerCommand::Nack { msg } => {
-                        println!(
-                            "NACK TOPIC => {}, message_id => {:?}",
-                            &msg.topic, msg.message_id.id
+                    RetryDecision::Retry(delay) => {
+                        debug!(attempt, ?delay, "nack delayed");
+                        let nack = ConsumerAck::Nack {
+                            topic: msg.topic,
+                            message_id: msg.message_id,
+                        };
+                        self.delayed_nacks
+                            .insert((Instant::now() + delay, self.next_sequence), (ack_tx, nack));
+                        self.next_sequence += 1;
+                    }
+                    RetryDecision::Exhausted(ExhaustedAction::Discard) => {
+                        warn!(attempt, %error, "giving up on message, discarding it");
+                        Metrics::global().discarded.with_label_values(&[msg.origin_topic()]).inc();
+                        send(
+                            &ack_tx,
+                            ConsumerAck::Ack {
+                                topic: msg.topic,
+                                message_id: msg.message_id,
+                            },
                         );
-                        self.pulsar_consumer
-                            .nack_with_id(&msg.topic, msg.message_id.id)
-                            .await
-                            .expect("should nack")
+                    }
+                    RetryDecision::Exhausted(ExhaustedAction::DeadLetter) => {
+                        let dead_letter = self.dead_letter.clone();
+
+                        let publication = async move {
+                            let topic = dead_letter.topic_for(msg.origin_topic());
+                            // the original is only acked once the dead letter is safely stored
+                            let ack = match dead_letter.publish(&msg, &error, attempt).await {
+                                Ok(()) => {
+                                    warn!(attempt, dead_letter_topic = %topic, %error, "message dead lettered");
+                                    Metrics::global().dead_lettered.with_label_values(&[msg.origin_topic()]).inc();
+                                    ConsumerAck::Ack {
+                                        topic: msg.topic,
+                                        message_id: msg.message_id,
+                                    }
+                                }
+                                Err(e) => {
+                                    error!(dead_letter_topic = %topic, error = %e, "dead lettering failed, nacking");
+                                    ConsumerAck::Nack {
+                                        topic: msg.topic,
+                                        message_id: msg.message_id,
+                                    }
+                                }
+                            };
+                            send(&ack_tx, ack);
+                        };
+                        self.publications.spawn(publication.instrument(ack_span.clone()));
                     }
                 }
             }
         }
     }
+
+    fn buffer_ack(&mut self, topic: String, message_id: MessageIdData, ack_tx: AckSender, handled_at: Instant) {
+        let position = self
+            .ack_buffers
+            .iter()
+            .position(|buffer| buffer.topic == topic && buffer.ack_tx.same_channel(&ack_tx));
+
+        let position = match position {
+            Some(position) => position,
+            None => {
+                self.ack_buffers.push(AckBuffer {
+                    topic,
+                    ack_tx,
+                    message_ids: Vec::with_capacity(self.ack_batch_size),
+                });
+                self.ack_buffers.len() - 1
+            }
+        };
+
+        let buffer = &mut self.ack_buffers[position];
+        buffer.message_ids.push(BufferedAck {
+            message_id,
+            queued_at: Instant::now(),
+            handled_at,
+        });
+
+        if buffer.message_ids.len() >= self.ack_batch_size {
+            let buffer = self.ack_buffers.swap_remove(position);
+            self.flush(buffer);
+        }
+    }
+
+    /// Flushes every buffer, buffers are dropped once flushed so they do not keep
+    /// the receivers ack channel open
+    fn flush_all(&mut self) {
+        for buffer in std::mem::take(&mut self.ack_buffers) {
+            self.flush(buffer);
+        }
+    }
+
+    fn flush(&self, buffer: AckBuffer) {
+        let now = Instant::now();
+        self.metrics
+            .record_flush(buffer.message_ids.iter().map(|ack| ack.queued_at), now);
+
+        let (message_ids, handled_at) = buffer
+            .message_ids
+            .into_iter()
+            .map(|ack| (ack.message_id, ack.handled_at.into_std()))
+            .unzip();
+        send(
+            &buffer.ack_tx,
+            ConsumerAck::AckAll {
+                topic: buffer.topic,
+                message_ids,
+                handled_at,
+            },
+        );
+    }
+
+    fn send_due_nacks(&mut self, now: Instant) {
+        while let Some(entry) = self.delayed_nacks.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let (ack_tx, nack) = entry.remove();
+            send(&ack_tx, nack);
+        }
+    }
 }
 
-pub struct AckerHandle {
-  pub acker_tx: mpsc::Sender<AckerCommand>,
+fn send(ack_tx: &AckSender, ack: ConsumerAck) {
+    if ack_tx.send(ack).is_err() {
+        error!("{}", Error::ChannelClosed("receiver ack"));
+    }
 }
 
-impl AckerHandle {
-    pub async fn new(pulsar_client: &Pulsar<TokioExecutor>, topics: Vec<String>) -> Self {
-        let (sender, receiver) = mpsc::channel(1000);
-        let mut actor = Acker::new(pulsar_client, receiver, topics).await;
-        tokio::spawn(async move { actor.handle_msg().await });
+fn log_task_result(result: Result<(), tokio::task::JoinError>) {
+    if let Err(e) = result {
+        error!(error = ?e, "acker publication task failed");
+    }
+}
 
-        Self { acker_tx: sender }
+pub struct AckerHandle<T> {
+  pub acker_tx: mpsc::Sender<AckerCommand<T>>,
+  pub metrics: Arc<AckerMetrics>,
+  task: JoinHandle<()>,
+}
+
+impl<T: DeserializeMessage + Send + 'static> AckerHandle<T> {
+    /// `broker` is used to publish dead and retry letters
+    pub async fn new<B: Broker>(config: &PipelineConfig, broker: B) -> Self {
+        let (sender, receiver) = mpsc::channel(config.acker_queue_capacity);
+        let dead_letter = DeadLetter::new(broker.clone(), config.dead_letter_topic.clone(), config.subscription.clone());
+        let metrics = Arc::new(AckerMetrics::default());
+        let mut actor = Acker::new(receiver, config, dead_letter, RetryLetter::new(broker), metrics.clone());
+        let task = tokio::spawn(TaskMonitor::instrument(&Metrics::global().monitors.acker, async move {
+            actor.handle_msg().await
+        }));
+
+        Self {
+            acker_tx: sender,
+            metrics,
+            task,
+        }
+    }
+
+    /// Drops this handle sender and waits for the acker to flush every pending command
+    ///
+    /// the acker only stops once every other sender clone (executors) is dropped too
+    pub async fn wait(self) {
+        drop(self.acker_tx);
+        if let Err(e) = self.task.await {
+            error!(error = ?e, "acker failed");
+        }
     }
 }
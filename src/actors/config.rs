@@ -0,0 +1,162 @@
+use std::{collections::BTreeMap, str::FromStr, time::Duration};
+
+use pulsar::SubType;
+
+use crate::{broker::SubscribeOptions, retry_letter::retry_topic};
+
+use super::{ConcurrencyLimit, RetryMode, RetryPolicy};
+
+/// Tuning for the Receivers -> Executor -> Acker pipeline
+///
+/// build one per topic to tune its receivers separately. The executor and the acker shared by
+/// every topic take one config, `topic_weights`, `topic_priorities` and `topic_retries` tell
+/// the topics apart there. `Default` matches the PoC values
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    /// maximum handler tasks running at the same time in the executor, whatever the topic
+    pub max_concurrency: usize,
+    /// share of the executor permits of each topic it is fed, 1 for the topics left out
+    pub topic_weights: BTreeMap<String, u32>,
+    /// topics with a higher priority get the executor permits first, 0 for the topics left out
+    pub topic_priorities: BTreeMap<String, u8>,
+    /// share of the permits still given to lower priorities while higher ones keep the executor
+    /// busy, between 0.0 (strict priority, lower topics can starve) and 1.0
+    pub low_priority_share: f64,
+    /// fixed at `max_concurrency` or adjusted at runtime up to it
+    pub concurrency: ConcurrencyLimit,
+    /// whether messages sharing a partition key are handled one at a time
+    pub ordering: ExecutionOrder,
+    /// capacity of the receiver -> executor channel
+    pub executor_queue_capacity: usize,
+    /// capacity of the executor -> acker channel
+    pub acker_queue_capacity: usize,
+    /// time given to in-flight tasks to finish on shutdown
+    pub drain_timeout: Duration,
+    pub consumer_name: String,
+    pub subscription: String,
+    pub subscription_type: SubType,
+    /// delay before the broker resends a message that was neither acked nor nacked
+    pub unacked_resend_delay: Option<Duration>,
+    /// backoff and attempts limit applied by the acker to failed messages
+    pub retry: RetryPolicy,
+    /// backoff of the topics tuned apart, `retry` for the topics left out. The retry mode is
+    /// the one of `retry` whatever the topic
+    pub topic_retries: BTreeMap<String, RetryPolicy>,
+    /// where exhausted messages are republished, `None` for `<topic>-<subscription>-DLQ`
+    pub dead_letter_topic: Option<String>,
+    /// acks buffered per topic by the acker before they are flushed, 1 flushes every ack
+    pub ack_batch_size: usize,
+    /// buffered acks are flushed at least this often
+    pub ack_flush_interval: Duration,
+    pub ack_mode: AckMode,
+}
+
+/// Order in which the handlers run the messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecutionOrder {
+    /// every message runs as soon as a permit is free, they can finish in any order
+    Unordered,
+    /// messages sharing a partition key run one at a time in arrival order, different keys
+    /// still run in parallel and messages without a key are unordered
+    ///
+    /// a nacked message is redelivered after the following ones of its key were handled
+    KeyOrdered,
+}
+
+impl FromStr for ExecutionOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "unordered" => Ok(ExecutionOrder::Unordered),
+            "key_ordered" => Ok(ExecutionOrder::KeyOrdered),
+            _ => Err(format!("unknown ordering {:?}, expected unordered or key_ordered", s)),
+        }
+    }
+}
+
+/// How the receivers apply the acks flushed by the acker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AckMode {
+    /// every message is acked on its own
+    Individual,
+    /// one cumulative ack up to the last message acked before the oldest one still in-flight,
+    /// Exclusive and Failover subscriptions only
+    Cumulative,
+}
+
+impl FromStr for AckMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "individual" => Ok(AckMode::Individual),
+            "cumulative" => Ok(AckMode::Cumulative),
+            _ => Err(format!("unknown ack mode {:?}, expected individual or cumulative", s)),
+        }
+    }
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrency: 100,
+            topic_weights: BTreeMap::new(),
+            topic_priorities: BTreeMap::new(),
+            low_priority_share: 0.1,
+            concurrency: ConcurrencyLimit::default(),
+            ordering: ExecutionOrder::Unordered,
+            executor_queue_capacity: 1000,
+            acker_queue_capacity: 1000,
+            drain_timeout: Duration::from_secs(30),
+            consumer_name: "test_consumer".to_string(),
+            subscription: "test_subscription".to_string(),
+            subscription_type: SubType::KeyShared,
+            unacked_resend_delay: Some(Duration::from_secs(60)),
+            retry: RetryPolicy::default(),
+            topic_retries: BTreeMap::new(),
+            dead_letter_topic: None,
+            ack_batch_size: 100,
+            ack_flush_interval: Duration::from_millis(100),
+            ack_mode: AckMode::Individual,
+        }
+    }
+}
+
+impl PipelineConfig {
+    pub fn topic_weight(&self, topic: &str) -> u32 {
+        self.topic_weights.get(topic).copied().unwrap_or(1)
+    }
+
+    pub fn topic_priority(&self, topic: &str) -> u8 {
+        self.topic_priorities.get(topic).copied().unwrap_or(0)
+    }
+
+    pub fn topic_retry(&self, topic: &str) -> &RetryPolicy {
+        self.topic_retries.get(topic).unwrap_or(&self.retry)
+    }
+
+    /// Subscription settings of this pipeline for `topics`
+    pub fn subscribe_options(&self, topics: Vec<String>) -> SubscribeOptions {
+        SubscribeOptions {
+            topics,
+            consumer_name: self.consumer_name.clone(),
+            subscription: self.subscription.clone(),
+            subscription_type: self.subscription_type,
+            unacked_resend_delay: self.unacked_resend_delay,
+        }
+    }
+
+    /// Retry letter subscription settings of this pipeline for `topic`, if retry letters are enabled
+    ///
+    /// the subscription is Shared whatever the main subscription type, pulsar only delays
+    /// the delivery of retry letters on Shared subscriptions
+    pub fn retry_subscribe_options(&self, topic: &str) -> Option<SubscribeOptions> {
+        (self.retry.mode == RetryMode::RetryLetter).then(|| SubscribeOptions {
+            subscription_type: SubType::Shared,
+            ..self.subscribe_options(vec![retry_topic(topic)])
+        })
+    }
+}
@@ -1,101 +1,696 @@
-use std::error::Error;
-use std::time::Duration;
-use std::{sync::Arc, thread::sleep};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::poll_fn,
+    sync::Arc,
+    task::Poll,
+    time::{Duration, Instant},
+};
 
-use chrono::Local;
-use tokio::sync::{mpsc, Semaphore};
+use pulsar::DeserializeMessage;
+use tokio::{
+    sync::mpsc,
+    task::{self, JoinHandle, JoinSet},
+    time::timeout,
+};
+use tokio_metrics::TaskMonitor;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
-use tokio::time::{sleep as sleep_tokio};
+use crate::{
+    error::Error,
+    handler::Handler,
+    latency::{Latencies, Stage},
+    metrics::{GaugeGuard, Metrics},
+    shutdown::Shutdown,
+};
 
-use super::{Acker, AckerCommand, ExecutorCommand};
+use super::{AckerCommand, ConcurrencyLimiter, ExecutionOrder, ExecutorCommand, LimiterPermit, PipelineConfig};
 
-pub struct Executor {
-    max_concurrency: usize,
-    acker_tx: mpsc::Sender<AckerCommand>,
-    executor_rx: mpsc::Receiver<ExecutorCommand>,
+pub struct Executor<T, H> {
+    limiter: Arc<ConcurrencyLimiter>,
+    ordering: ExecutionOrder,
+    queue_capacity: usize,
+    drain_timeout: Duration,
+    handler: Arc<H>,
+    acker_tx: mpsc::Sender<AckerCommand<T>>,
+    lanes: Vec<Lane<T>>,
+    dispatched: u64,
+    low_priority_share: f64,
+    // share accrued by the lower lanes while a higher one took the permits, one permit per unit
+    low_priority_credit: f64,
 }
 
-impl Executor {
+impl<T, H> Executor<T, H>
+where
+    T: DeserializeMessage + Send + 'static,
+    H: Handler<T>,
+{
+    /// `executor_rx` holds the channel of every topic fed to the executor
     pub fn new(
-        acker_tx: mpsc::Sender<AckerCommand>,
-        executor_rx: mpsc::Receiver<ExecutorCommand>,
+        handler: H,
+        acker_tx: mpsc::Sender<AckerCommand<T>>,
+        executor_rx: Vec<(String, mpsc::Receiver<ExecutorCommand<T>>)>,
+        config: &PipelineConfig,
     ) -> Self {
+        let lanes = executor_rx
+            .into_iter()
+            .map(|(topic, rx)| Lane::new(config.topic_weight(&topic), config.topic_priority(&topic), rx))
+            .collect();
+
         Self {
+            handler: Arc::new(handler),
             acker_tx,
-            executor_rx,
-            // TODO
-            max_concurrency: 100,
-        }
-    }
-
-    pub async fn process(&mut self) {
-        let semaphore = Arc::new(Semaphore::new(self.max_concurrency));
-
-        while let Some(msg) = self.executor_rx.recv().await {
-            println!("[EXECUTOR] reading msg");
-
-            let sender = self.acker_tx.clone();
-            let permit = semaphore.clone().acquire_owned().await.unwrap();
-
-            tokio::spawn(async move {
-                match msg {
-                    ExecutorCommand::Process { msg } => {
-                        match msg.deserialize() {
-                            Ok(data) => {
-                                println!(
-                                    "[EXECUTOR] processing data: {:?}, timestamp: {:?}",
-                                    data,
-                                    Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string()
-                                );
-
-                                // All the algorithm needs to do is:
-                                // 1. Fetch dependent Data (mostly I/O)
-                                // 2. Calculate Operations (CPU)
-                                // 3. Calculate KPIs (CPU)
-                                // 4. Write it into the database (mostly I/O)
-
-                                // Simulate some I/O work
-                                sleep_tokio(Duration::from_millis(100)).await;
-
-                                // Simulate some CPU work
-                                sleep(Duration::from_millis(10));
-
-                                sender
-                                    .send(AckerCommand::Ack { msg })
-                                    .await
-                                    .expect("to send ack");
-                            }
-                            Err(e) => {
-                                sender
-                                    .send(AckerCommand::Nack { msg })
-                                    .await
-                                    .expect("to send nack");
-                                return Err(Box::<dyn Error + Send + Sync>::from(format!(
-                                    "Deserialization failed: {}",
-                                    e
-                                )));
-                            }
-                        };
+            lanes,
+            dispatched: 0,
+            low_priority_share: config.low_priority_share.clamp(0.0, 1.0),
+            low_priority_credit: 0.0,
+            drain_timeout: config.drain_timeout,
+            limiter: ConcurrencyLimiter::new(&config.concurrency, config.max_concurrency),
+            ordering: config.ordering,
+            queue_capacity: config.executor_queue_capacity,
+        }
+    }
+
+    /// Process messages until shutdown is requested or every sender is dropped
+    ///
+    /// the permits are shared by every topic. Each free permit goes to the highest priority
+    /// topic with a message ready, among them to the one with the fewest tasks running for its
+    /// weight, the one served the longest ago on a tie, so a busy topic gets at least its
+    /// weighted share of the permits of its priority whatever the rate of the others. A topic
+    /// without messages ready leaves its share to the others. While higher priorities keep
+    /// the permits busy, `low_priority_share` of them still go to the lower topics in turn so
+    /// they are never starved.
+    ///
+    /// in key-ordered mode a message whose key is already being handled waits for it, up to
+    /// the executor queue capacity per topic, then the next one of the key starts once it
+    /// finished.
+    ///
+    /// on shutdown in-flight tasks get up to `drain_timeout` to finish, the ones still
+    /// running after that are aborted and their messages redelivered by the broker, like
+    /// the messages not started yet
+    pub async fn process(&mut self, mut shutdown: Shutdown) {
+        let mut tasks = JoinSet::new();
+        // lane of every running task
+        let mut running: HashMap<task::Id, usize> = HashMap::new();
+
+        loop {
+            let ready = self.lanes.iter().any(|lane| !lane.ready.is_empty());
+            tokio::select! {
+                _ = shutdown.recv() => break,
+                // reap finished tasks so the set does not grow unbounded
+                Some(result) = tasks.join_next_with_id() => {
+                    let id = match &result {
+                        Ok((id, ())) => *id,
+                        Err(e) => e.id(),
+                    };
+                    log_task_result(result.map(|_| ()));
+                    if let Some(lane) = running.remove(&id) {
+                        self.lanes[lane].finished(id);
                     }
                 }
-                drop(permit);
-                Ok(())
-            });
+                received = recv_any(&mut self.lanes, self.queue_capacity) => match received {
+                    Some((lane, cmd)) => self.lanes[lane].push(cmd, self.ordering),
+                    None => break,
+                },
+                // the semaphore of the limiter is never closed
+                permit = self.limiter.acquire(), if ready => {
+                    let Ok(permit) = permit else {
+                        break;
+                    };
+                    let Some((lane, cmd, key)) = self.next_ready() else {
+                        continue;
+                    };
+                    let task = self.spawn(&mut tasks, cmd, permit);
+                    running.insert(task, lane);
+                    if let Some(key) = key {
+                        self.lanes[lane].keys.started(task, key);
+                    }
+                }
+            }
+        }
+
+        let waiting: usize = self.lanes.iter_mut().map(Lane::close).sum();
+        if waiting > 0 {
+            Metrics::global().executor_queue_depth.sub(waiting as i64);
+            info!(waiting, "executor dropping messages not started yet, they will be redelivered");
+        }
+
+        info!(in_flight = tasks.len(), "executor draining in-flight tasks");
+        let drained = timeout(self.drain_timeout, async {
+            while let Some(result) = tasks.join_next().await {
+                log_task_result(result);
+            }
+        })
+        .await;
+
+        if drained.is_err() {
+            warn!(in_flight = tasks.len(), "executor drain timeout reached, aborting in-flight tasks");
+            tasks.shutdown().await;
         }
     }
+
+    /// Takes the next message of the lane owed a permit the most
+    ///
+    /// the highest priority with a message ready wins, except for the `low_priority_share` of
+    /// the permits given to a lower lane while several priorities compete
+    fn next_ready(&mut self) -> Option<(usize, ExecutorCommand<T>, Option<String>)> {
+        let top = self
+            .lanes
+            .iter()
+            .filter(|lane| !lane.ready.is_empty())
+            .map(|lane| lane.priority)
+            .max()?;
+
+        let contended = self
+            .lanes
+            .iter()
+            .any(|lane| !lane.ready.is_empty() && lane.priority < top);
+        let lower = contended && {
+            self.low_priority_credit += self.low_priority_share;
+            self.low_priority_credit >= 1.0
+        };
+
+        let ready = self.lanes.iter_mut().enumerate().filter(|(_, lane)| !lane.ready.is_empty());
+        let (index, lane) = if lower {
+            self.low_priority_credit -= 1.0;
+            // whatever their priority, the lower lanes take turns
+            ready
+                .filter(|(_, lane)| lane.priority < top)
+                .min_by_key(|(_, lane)| lane.last_dispatch)?
+        } else {
+            ready
+                .filter(|(_, lane)| lane.priority == top)
+                // running / weight compared without dividing
+                .min_by(|(_, a), (_, b)| {
+                    (a.running as u64 * b.weight)
+                        .cmp(&(b.running as u64 * a.weight))
+                        .then(a.last_dispatch.cmp(&b.last_dispatch))
+                })?
+        };
+        let (cmd, key) = lane.ready.pop_front()?;
+
+        self.dispatched += 1;
+        lane.last_dispatch = self.dispatched;
+        lane.running += 1;
+        Some((index, cmd, key))
+    }
+
+    fn spawn(&self, tasks: &mut JoinSet<()>, cmd: ExecutorCommand<T>, permit: LimiterPermit) -> task::Id {
+        Metrics::global().executor_queue_depth.dec();
+        let ExecutorCommand::Process { msg, ack_tx } = cmd;
+        let span = msg.span().clone();
+        debug!(parent: &span, "executor received message");
+
+        let sender = self.acker_tx.clone();
+        let handler = self.handler.clone();
+
+        let task = async move {
+            let running = GaugeGuard::inc(&Metrics::global().permits_in_use);
+            let started = Instant::now();
+            let result = handler.handle(&msg).instrument(info_span!("handle")).await;
+            Metrics::global().record_handled(&msg.topic, started, &result);
+            let handled_at = Instant::now();
+            Latencies::global().record(&msg.topic, Stage::ReceiveToHandled, handled_at - msg.received_at());
+
+            permit.record(&result);
+
+            let cmd = match result {
+                Ok(()) => AckerCommand::Ack { msg, ack_tx, handled_at },
+                Err(e) => {
+                    warn!(error = %e, "handler failed");
+                    AckerCommand::Nack { msg, ack_tx, error: e }
+                }
+            };
+
+            if sender.send(cmd).await.is_err() {
+                error!("{}", Error::ChannelClosed("acker"));
+            }
+            drop(running);
+            drop(permit);
+        };
+        // tracing's `Instrument` would shadow the monitor method
+        tasks
+            .spawn(TaskMonitor::instrument(&Metrics::global().monitors.executor, task.instrument(span)))
+            .id()
+    }
+}
+
+/// Next message of any lane with room for it, `None` once every lane is closed
+///
+/// a lane holds a single message ready at a time so the messages of a busy topic wait in its
+/// channel, where they push back on its receivers only
+async fn recv_any<T>(lanes: &mut [Lane<T>], queue_capacity: usize) -> Option<(usize, ExecutorCommand<T>)> {
+    poll_fn(|cx| {
+        for (index, lane) in lanes.iter_mut().enumerate() {
+            if lane.closed || !lane.ready.is_empty() || lane.keys.waiting() >= queue_capacity {
+                continue;
+            }
+            match lane.rx.poll_recv(cx) {
+                Poll::Ready(Some(cmd)) => return Poll::Ready(Some((index, cmd))),
+                Poll::Ready(None) => lane.closed = true,
+                Poll::Pending => {}
+            }
+        }
+
+        // lanes without room are polled again once a task finished or a permit was taken
+        if lanes.iter().all(|lane| lane.closed) {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+fn log_task_result(result: Result<(), tokio::task::JoinError>) {
+    if let Err(e) = result {
+        error!(error = ?e, "executor task failed");
+    }
 }
 
+/// Keys being handled in key-ordered mode, with the messages waiting for them in arrival order
+struct KeyQueues<T> {
+    // a key is in there while one of its messages is being handled
+    waiting: HashMap<String, VecDeque<ExecutorCommand<T>>>,
+    running: HashMap<task::Id, String>,
+    len: usize,
+}
+
+impl<T> Default for KeyQueues<T> {
+    fn default() -> Self {
+        Self {
+            waiting: HashMap::new(),
+            running: HashMap::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> KeyQueues<T> {
+    /// Messages waiting for the one of their key being handled
+    fn waiting(&self) -> usize {
+        self.len
+    }
+
+    /// Hands the message back when no message of its key is being handled, queues it otherwise
+    fn push(&mut self, key: String, cmd: ExecutorCommand<T>) -> Option<(ExecutorCommand<T>, Option<String>)> {
+        match self.waiting.get_mut(&key) {
+            Some(queue) => {
+                queue.push_back(cmd);
+                self.len += 1;
+                None
+            }
+            None => Some((cmd, Some(key))),
+        }
+    }
+
+    /// The task handling a message of `key` was spawned
+    fn started(&mut self, id: task::Id, key: String) {
+        self.waiting.entry(key.clone()).or_default();
+        self.running.insert(id, key);
+    }
+
+    /// The task finished, returns the next message of its key if any
+    fn finished(&mut self, id: task::Id) -> Option<(ExecutorCommand<T>, Option<String>)> {
+        let key = self.running.remove(&id)?;
+        let queue = self.waiting.get_mut(&key)?;
+
+        match queue.pop_front() {
+            Some(cmd) => {
+                self.len -= 1;
+                Some((cmd, Some(key)))
+            }
+            None => {
+                self.waiting.remove(&key);
+                None
+            }
+        }
+    }
+}
+
+
+/// Messages of one topic, fed by its receivers
+struct Lane<T> {
+    weight: u64,
+    priority: u8,
+    rx: mpsc::Receiver<ExecutorCommand<T>>,
+    closed: bool,
+    /// messages free to start, with their key in key-ordered mode
+    ready: VecDeque<(ExecutorCommand<T>, Option<String>)>,
+    keys: KeyQueues<T>,
+    running: usize,
+    /// dispatch sequence of the last message started
+    last_dispatch: u64,
+}
+
+impl<T> Lane<T> {
+    fn new(weight: u32, priority: u8, rx: mpsc::Receiver<ExecutorCommand<T>>) -> Self {
+        Self {
+            weight: u64::from(weight.max(1)),
+            priority,
+            rx,
+            closed: false,
+            ready: VecDeque::new(),
+            keys: KeyQueues::default(),
+            running: 0,
+            last_dispatch: 0,
+        }
+    }
+
+    /// Stops receiving, returns the messages not started yet, including the ones left in the channel
+    fn close(&mut self) -> usize {
+        self.rx.close();
+        let mut waiting = self.ready.len() + self.keys.waiting();
+        while self.rx.try_recv().is_ok() {
+            waiting += 1;
+        }
+        waiting
+    }
+
+    fn push(&mut self, cmd: ExecutorCommand<T>, ordering: ExecutionOrder) {
+        let ExecutorCommand::Process { msg, .. } = &cmd;
+        let key = match ordering {
+            ExecutionOrder::KeyOrdered => msg.key().map(str::to_string),
+            ExecutionOrder::Unordered => None,
+        };
+        match key {
+            Some(key) => {
+                if let Some(ready) = self.keys.push(key, cmd) {
+                    self.ready.push_back(ready);
+                }
+            }
+            None => self.ready.push_back((cmd, None)),
+        }
+    }
+
+    /// The task `id` of this lane finished, the next message of its key becomes ready
+    fn finished(&mut self, id: task::Id) {
+        self.running -= 1;
+        if let Some(next) = self.keys.finished(id) {
+            self.ready.push_back(next);
+        }
+    }
+}
+
+pub struct ExecutorHandle<T> {
+    senders: Vec<(String, mpsc::Sender<ExecutorCommand<T>>)>,
+    task: JoinHandle<()>,
+}
+
+impl<T: DeserializeMessage + Send + 'static> ExecutorHandle<T> {
+    /// Spawns one executor shared by `topics`, each one gets its own channel
+    pub async fn new<H: Handler<T>>(
+        handler: H,
+        topics: &[String],
+        acker_tx: mpsc::Sender<AckerCommand<T>>,
+        config: &PipelineConfig,
+        shutdown: Shutdown,
+    ) -> Self {
+        let (senders, receivers) = topics
+            .iter()
+            .map(|topic| {
+                let (sender, receiver) = mpsc::channel(config.executor_queue_capacity);
+                ((topic.clone(), sender), (topic.clone(), receiver))
+            })
+            .unzip();
+        let mut actor = Executor::new(handler, acker_tx, receivers, config);
+        let task = tokio::spawn(async move { actor.process(shutdown).await });
+
+        Self { senders, task }
+    }
 
-pub struct ExecutorHandle {
-  pub executor_tx: mpsc::Sender<ExecutorCommand>,
+    /// Channel of every topic, for the receivers of that topic
+    pub fn senders(&self) -> &[(String, mpsc::Sender<ExecutorCommand<T>>)] {
+        &self.senders
+    }
+
+    /// Waits for the executor to drain its in-flight tasks after shutdown was requested
+    pub async fn wait(self) {
+        drop(self.senders);
+        if let Err(e) = self.task.await {
+            error!(error = ?e, "executor failed");
+        }
+    }
 }
 
-impl ExecutorHandle {
-    pub async fn new(acker_tx: mpsc::Sender<AckerCommand>) -> Self {
-        let (sender, receiver) = mpsc::channel(1000);
-        let mut actor = Executor::new(acker_tx, receiver);
-        tokio::spawn(async move { actor.process().await });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handler::HandlerResult,
+        message::Message,
+        shutdown,
+        test_support::{message, Recorder},
+        TestData,
+    };
+
+    struct Noop;
+
+    impl Handler<TestData> for Noop {
+        async fn handle(&self, _msg: &Message<TestData>) -> HandlerResult {
+            Ok(())
+        }
+    }
+
+    type TestExecutor = Executor<TestData, Noop>;
+
+    /// Executor fed by a lane per topic, in order, with their senders
+    fn executor(
+        topics: &[&str],
+        config: &PipelineConfig,
+    ) -> (TestExecutor, Vec<mpsc::Sender<ExecutorCommand<TestData>>>) {
+        let (acker_tx, _) = mpsc::channel(1);
+        let (senders, receivers) = topics
+            .iter()
+            .map(|topic| {
+                let (sender, receiver) = mpsc::channel(config.executor_queue_capacity);
+                (sender, (topic.to_string(), receiver))
+            })
+            .unzip();
+
+        (Executor::new(Noop, acker_tx, receivers, config), senders)
+    }
+
+    fn command(topic: &str, key: Option<&str>) -> ExecutorCommand<TestData> {
+        let (ack_tx, _) = mpsc::unbounded_channel();
+
+        ExecutorCommand::Process {
+            msg: message(topic, key, 0),
+            ack_tx,
+        }
+    }
+
+    /// Runs entries 0 to 2 of the keys `a` and `b` through an executor, arrival order interleaved
+    async fn handle_keyed(ordering: ExecutionOrder) -> Recorder {
+        let config = PipelineConfig {
+            ordering,
+            max_concurrency: 8,
+            ..Default::default()
+        };
+        let recorder = Recorder::default();
+        let (acker_tx, mut acker_rx) = mpsc::channel(8);
+        let (trigger, shutdown) = shutdown::channel();
+        let topics = ["topic".to_string()];
+        let executor = ExecutorHandle::new(recorder.clone(), &topics, acker_tx, &config, shutdown).await;
+
+        let sender = executor.senders()[0].1.clone();
+        let (ack_tx, _ack_rx) = mpsc::unbounded_channel();
+        for entry in 0..3 {
+            for key in ["a", "b"] {
+                let cmd = ExecutorCommand::Process {
+                    msg: message("topic", Some(key), entry),
+                    ack_tx: ack_tx.clone(),
+                };
+                sender.send(cmd).await.unwrap();
+            }
+        }
+        for _ in 0..6 {
+            assert!(matches!(acker_rx.recv().await, Some(AckerCommand::Ack { .. })));
+        }
+
+        drop(sender);
+        trigger.trigger();
+        executor.wait().await;
+        recorder
+    }
+
+    #[tokio::test]
+    async fn same_key_messages_overtake_each_other_when_unordered() {
+        let recorder = handle_keyed(ExecutionOrder::Unordered).await;
+
+        assert_eq!(recorder.finished("a"), [2, 1, 0]);
+        assert_eq!(recorder.finished("b"), [2, 1, 0]);
+        assert_eq!(recorder.peak(), 6);
+    }
+
+    #[tokio::test]
+    async fn same_key_messages_finish_in_arrival_order_when_key_ordered() {
+        let recorder = handle_keyed(ExecutionOrder::KeyOrdered).await;
+
+        assert_eq!(recorder.finished("a"), [0, 1, 2]);
+        assert_eq!(recorder.finished("b"), [0, 1, 2]);
+        // one message per key at a time, both keys at once
+        assert_eq!(recorder.peak(), 2);
+    }
+
+    /// Starts `dispatches` messages with at most `permits` tasks running, the oldest one
+    /// finishing first, then lets them all finish. Returns the messages started per lane
+    ///
+    /// the `busy` lanes always have a message ready, like a topic with a backlog
+    fn run(executor: &mut TestExecutor, busy: &[usize], permits: usize, dispatches: usize) -> Vec<usize> {
+        let mut started = vec![0; executor.lanes.len()];
+        let mut running = VecDeque::new();
+
+        for _ in 0..dispatches {
+            if running.len() == permits {
+                let lane: usize = running.pop_front().unwrap();
+                executor.lanes[lane].running -= 1;
+            }
+            for &lane in busy {
+                if executor.lanes[lane].ready.is_empty() {
+                    executor.lanes[lane].push(command("topic", None), ExecutionOrder::Unordered);
+                }
+            }
+
+            let (lane, _, _) = executor.next_ready().unwrap();
+            started[lane] += 1;
+            running.push_back(lane);
+        }
+
+        for lane in running {
+            executor.lanes[lane].running -= 1;
+        }
+        started
+    }
+
+    fn assert_near(actual: usize, expected: usize, tolerance: usize) {
+        assert!(
+            actual.abs_diff(expected) <= tolerance,
+            "expected {} +/- {}, got {}",
+            expected,
+            tolerance,
+            actual
+        );
+    }
+
+    #[test]
+    fn busy_topics_share_the_permits_by_weight() {
+        let config = PipelineConfig {
+            topic_weights: [("heavy".to_string(), 3)].into(),
+            ..Default::default()
+        };
+        let (mut executor, _senders) = executor(&["heavy", "light"], &config);
+
+        let started = run(&mut executor, &[0, 1], 8, 400);
+        assert_near(started[0], 300, 4);
+        assert_near(started[1], 100, 4);
+    }
+
+    #[test]
+    fn idle_topic_leaves_its_share_to_the_busy_ones() {
+        let (mut executor, _senders) = executor(&["a", "b", "idle"], &PipelineConfig::default());
+
+        let started = run(&mut executor, &[0, 1], 8, 200);
+        assert_eq!(started[2], 0);
+        assert_near(started[0], 100, 2);
+        assert_near(started[1], 100, 2);
+
+        // once busy it gets its share back
+        let started = run(&mut executor, &[0, 1, 2], 8, 300);
+        for started in started {
+            assert_near(started, 100, 6);
+        }
+    }
+
+    #[test]
+    fn nothing_ready_nothing_dispatched() {
+        let (mut executor, _senders) = executor(&["a", "b"], &PipelineConfig::default());
+
+        assert!(executor.next_ready().is_none());
+    }
+
+    fn prioritized(low_priority_share: f64) -> PipelineConfig {
+        PipelineConfig {
+            topic_priorities: [("high".to_string(), 2), ("mid".to_string(), 1)].into(),
+            low_priority_share,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn strict_priority_without_low_priority_share() {
+        let (mut executor, _senders) = executor(&["high", "low"], &prioritized(0.0));
+
+        let started = run(&mut executor, &[0, 1], 8, 200);
+        assert_eq!(started, [200, 0]);
+    }
+
+    #[test]
+    fn lower_priorities_get_their_share_under_contention() {
+        for (share, low) in [(0.1, 20), (0.25, 50), (0.5, 100)] {
+            let (mut executor, _senders) = executor(&["high", "low"], &prioritized(share));
+
+            let started = run(&mut executor, &[0, 1], 8, 200);
+            assert_near(started[1], low, 1);
+        }
+    }
+
+    #[test]
+    fn lower_priorities_take_turns_on_their_share() {
+        let (mut executor, _senders) = executor(&["high", "mid", "low"], &prioritized(0.2));
+
+        let started = run(&mut executor, &[0, 1, 2], 8, 400);
+        assert_near(started[0], 320, 1);
+        assert_near(started[1], 40, 1);
+        assert_near(started[2], 40, 1);
+    }
+
+    #[test]
+    fn lower_priority_alone_takes_every_permit() {
+        let (mut executor, _senders) = executor(&["high", "low"], &prioritized(0.1));
+
+        let started = run(&mut executor, &[1], 8, 100);
+        assert_eq!(started, [0, 100]);
+        // no credit piled up while the high priority topic was idle
+        let started = run(&mut executor, &[0, 1], 8, 100);
+        assert_near(started[1], 10, 1);
+    }
+
+    #[tokio::test]
+    async fn closed_lane_counts_every_message_not_started() {
+        let config = PipelineConfig {
+            ordering: ExecutionOrder::KeyOrdered,
+            ..Default::default()
+        };
+        let (mut executor, senders) = executor(&["topic"], &config);
+        let lane = &mut executor.lanes[0];
+
+        // the first message of the key runs, the next two wait for it
+        lane.push(command("topic", Some("key")), config.ordering);
+        lane.ready.pop_front().unwrap();
+        lane.running += 1;
+        lane.keys.started(tokio::spawn(async {}).id(), "key".to_string());
+        lane.push(command("topic", Some("key")), config.ordering);
+        lane.push(command("topic", Some("key")), config.ordering);
+        // another key is ready
+        lane.push(command("topic", Some("other")), config.ordering);
+        // and three were not received yet
+        for _ in 0..3 {
+            senders[0].send(command("topic", None)).await.unwrap();
+        }
+        assert_eq!((lane.ready.len(), lane.keys.waiting()), (1, 2));
+
+        assert_eq!(lane.close(), 6);
+        assert!(senders[0].try_send(command("topic", None)).is_err());
+    }
+
+    #[tokio::test]
+    async fn all_lanes_closed_ends_the_receiving() {
+        let (mut executor, senders) = executor(&["a", "b"], &PipelineConfig::default());
+        senders[1].send(command("b", None)).await.unwrap();
+        drop(senders);
 
-        Self { executor_tx: sender }
+        let received = recv_any(&mut executor.lanes, 1).await;
+        assert!(matches!(received, Some((1, _))));
+        assert!(recv_any(&mut executor.lanes, 1).await.is_none());
     }
 }
@@ -0,0 +1,406 @@
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+use crate::{handler::HandlerResult, metrics::Metrics};
+
+/// How the executor sizes its pool of handler permits
+///
+/// in AIMD mode the limit starts at `min` and doubles after every window of `limit` handled
+/// messages until the first congestion, then grows by one per window. A window is congested
+/// when more than `error_threshold` of its messages failed, or when its mean handler latency
+/// exceeds `latency_tolerance` times the baseline, the lowest mean latency seen so far. The
+/// limit is then multiplied by `backoff`. It only grows when the window used every permit,
+/// and always stays between `min` and the pipeline `max_concurrency`.
+///
+/// a window still congested at `min` means the handler itself got slower, e.g. a slower
+/// database, the baseline is reset to its latency
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit {
+    pub mode: LimitMode,
+    pub min: usize,
+    /// factor applied to the limit on congestion, between 0.0 and 1.0
+    pub backoff: f64,
+    pub latency_tolerance: f64,
+    /// share of failed messages in a window, between 0.0 and 1.0
+    pub error_threshold: f64,
+}
+
+/// Whether the executor permits are fixed or adjusted at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitMode {
+    /// `max_concurrency` permits
+    Fixed,
+    /// additive increase, multiplicative decrease on handler latency and error rate
+    Aimd,
+}
+
+impl FromStr for LimitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(LimitMode::Fixed),
+            "aimd" => Ok(LimitMode::Aimd),
+            _ => Err(format!("unknown concurrency mode {:?}, expected fixed or aimd", s)),
+        }
+    }
+}
+
+impl Default for ConcurrencyLimit {
+    fn default() -> Self {
+        Self {
+            mode: LimitMode::Fixed,
+            min: 1,
+            backoff: 0.9,
+            latency_tolerance: 2.0,
+            error_threshold: 0.1,
+        }
+    }
+}
+
+/// Semaphore whose permit count follows a `ConcurrencyLimit`
+///
+/// the limit of every limiter is added to the `executor_concurrency_limit` gauge
+pub struct ConcurrencyLimiter {
+    semaphore: Arc<Semaphore>,
+    policy: ConcurrencyLimit,
+    max: usize,
+    state: Mutex<LimiterState>,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    limit: usize,
+    // permits to forget as they come back, when the limit shrank below the permits in use
+    debt: usize,
+    in_flight: usize,
+    slow_start: bool,
+    baseline: Option<Duration>,
+    window: Window,
+}
+
+/// Handler outcomes since the last limit update
+#[derive(Debug, Default)]
+struct Window {
+    handled: usize,
+    failed: usize,
+    latency: Duration,
+    peak_in_flight: usize,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(policy: &ConcurrencyLimit, max_concurrency: usize) -> Arc<Self> {
+        let max = max_concurrency.max(1);
+        let limit = match policy.mode {
+            LimitMode::Fixed => max,
+            LimitMode::Aimd => policy.min.clamp(1, max),
+        };
+        Metrics::global().concurrency_limit.add(limit as i64);
+
+        Arc::new(Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            policy: policy.clone(),
+            max,
+            state: Mutex::new(LimiterState {
+                limit,
+                debt: 0,
+                in_flight: 0,
+                slow_start: true,
+                baseline: None,
+                window: Window::default(),
+            }),
+        })
+    }
+
+    /// Waits for a permit, it is given back when the returned guard is dropped
+    pub async fn acquire(self: &Arc<Self>) -> Result<LimiterPermit, AcquireError> {
+        let permit = self.semaphore.clone().acquire_owned().await?;
+
+        let mut state = self.lock();
+        state.in_flight += 1;
+        state.window.peak_in_flight = state.window.peak_in_flight.max(state.in_flight);
+        drop(state);
+
+        Ok(LimiterPermit {
+            permit: Some(permit),
+            limiter: self.clone(),
+            started: Instant::now(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LimiterState> {
+        // the state is always left consistent, a panic while holding the lock can be ignored
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn record(&self, latency: Duration, failed: bool) {
+        if self.policy.mode == LimitMode::Fixed {
+            return;
+        }
+
+        let mut state = self.lock();
+        state.window.handled += 1;
+        state.window.failed += usize::from(failed);
+        state.window.latency += latency;
+        if state.window.handled < state.limit {
+            return;
+        }
+
+        let window = std::mem::take(&mut state.window);
+        let mean_latency = window.latency / window.handled as u32;
+        let error_rate = window.failed as f64 / window.handled as f64;
+        let baseline = match state.baseline {
+            Some(baseline) if baseline <= mean_latency => baseline,
+            _ => mean_latency,
+        };
+        state.baseline = Some(baseline);
+
+        let congested = error_rate > self.policy.error_threshold
+            || mean_latency.as_secs_f64() > baseline.as_secs_f64() * self.policy.latency_tolerance;
+        let limit = state.limit;
+        let min = self.policy.min.clamp(1, self.max);
+        if congested && limit == min {
+            state.baseline = Some(mean_latency);
+        }
+
+        let new_limit = if congested {
+            state.slow_start = false;
+            (limit as f64 * self.policy.backoff.clamp(0.0, 1.0)) as usize
+        } else if window.peak_in_flight >= limit {
+            if state.slow_start {
+                limit * 2
+            } else {
+                limit + 1
+            }
+        } else {
+            limit
+        };
+        let new_limit = new_limit.clamp(min, self.max);
+        if new_limit == limit {
+            return;
+        }
+
+        debug!(limit = new_limit, previous = limit, ?mean_latency, ?baseline, error_rate, "concurrency limit updated");
+        state.limit = new_limit;
+        Metrics::global()
+            .concurrency_limit
+            .add(new_limit as i64 - limit as i64);
+
+        if new_limit > limit {
+            let mut added = new_limit - limit;
+            let repaid = added.min(state.debt);
+            state.debt -= repaid;
+            added -= repaid;
+            self.semaphore.add_permits(added);
+        } else {
+            let removed = limit - new_limit;
+            let forgotten = self.semaphore.forget_permits(removed);
+            state.debt += removed - forgotten;
+        }
+    }
+}
+
+impl Drop for ConcurrencyLimiter {
+    fn drop(&mut self) {
+        Metrics::global().concurrency_limit.sub(self.lock().limit as i64);
+    }
+}
+
+/// Permit of a `ConcurrencyLimiter`, report the handler result with `record`
+pub struct LimiterPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    limiter: Arc<ConcurrencyLimiter>,
+    started: Instant,
+}
+
+impl LimiterPermit {
+    /// Feeds the handler latency, since the permit was acquired, and outcome to the limiter
+    pub fn record(&self, result: &HandlerResult) {
+        self.limiter.record(self.started.elapsed(), result.is_err());
+    }
+}
+
+impl Drop for LimiterPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.lock();
+        state.in_flight -= 1;
+        if state.debt > 0 {
+            state.debt -= 1;
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASELINE: Duration = Duration::from_millis(10);
+
+    fn aimd(min: usize, max_concurrency: usize) -> Arc<ConcurrencyLimiter> {
+        let policy = ConcurrencyLimit {
+            mode: LimitMode::Aimd,
+            min,
+            backoff: 0.5,
+            latency_tolerance: 2.0,
+            error_threshold: 0.1,
+        };
+        ConcurrencyLimiter::new(&policy, max_concurrency)
+    }
+
+    fn limit(limiter: &ConcurrencyLimiter) -> usize {
+        limiter.lock().limit
+    }
+
+    /// Records a window using every permit, `failed` of its messages failing
+    fn window(limiter: &ConcurrencyLimiter, latency: Duration, failed: usize) -> usize {
+        let handled = limit(limiter);
+        limiter.lock().window.peak_in_flight = handled;
+        for i in 0..handled {
+            limiter.record(latency, i < failed);
+        }
+        limit(limiter)
+    }
+
+    #[test]
+    fn fixed_limit_never_moves() {
+        let limiter = ConcurrencyLimiter::new(&ConcurrencyLimit::default(), 8);
+
+        assert_eq!(window(&limiter, BASELINE * 10, 8), 8);
+        assert_eq!(limiter.semaphore.available_permits(), 8);
+    }
+
+    #[test]
+    fn slow_start_doubles_the_limit() {
+        let limiter = aimd(1, 64);
+
+        let limits: Vec<usize> = (0..4).map(|_| window(&limiter, BASELINE, 0)).collect();
+        assert_eq!(limits, [2, 4, 8, 16]);
+        assert_eq!(limiter.semaphore.available_permits(), 16);
+    }
+
+    #[test]
+    fn grows_by_one_after_the_first_congestion() {
+        let limiter = aimd(1, 64);
+        for _ in 0..3 {
+            window(&limiter, BASELINE, 0);
+        }
+        assert_eq!(window(&limiter, BASELINE * 3, 0), 4);
+
+        let limits: Vec<usize> = (0..3).map(|_| window(&limiter, BASELINE, 0)).collect();
+        assert_eq!(limits, [5, 6, 7]);
+        assert_eq!(limiter.semaphore.available_permits(), 7);
+    }
+
+    #[test]
+    fn does_not_grow_without_using_every_permit() {
+        let limiter = aimd(4, 64);
+
+        limiter.lock().window.peak_in_flight = 3;
+        for _ in 0..4 {
+            limiter.record(BASELINE, false);
+        }
+        assert_eq!(limit(&limiter), 4);
+    }
+
+    #[test]
+    fn backs_off_on_error_rate() {
+        let limiter = aimd(1, 64);
+        for _ in 0..3 {
+            window(&limiter, BASELINE, 0);
+        }
+
+        // one failure in 8 is above the 10% threshold
+        assert_eq!(window(&limiter, BASELINE, 1), 4);
+        assert_eq!(limiter.semaphore.available_permits(), 4);
+    }
+
+    #[test]
+    fn backs_off_on_latency() {
+        let limiter = aimd(2, 64);
+        assert_eq!(window(&limiter, BASELINE, 0), 4);
+        assert_eq!(window(&limiter, BASELINE * 2, 0), 8);
+
+        assert_eq!(window(&limiter, BASELINE * 2 + Duration::from_millis(1), 0), 4);
+    }
+
+    #[test]
+    fn stays_between_min_and_max() {
+        let limiter = aimd(2, 5);
+
+        assert_eq!(window(&limiter, BASELINE, 0), 4);
+        assert_eq!(window(&limiter, BASELINE, 0), 5);
+        assert_eq!(window(&limiter, BASELINE, 0), 5);
+
+        assert_eq!(window(&limiter, BASELINE, 5), 2);
+        assert_eq!(window(&limiter, BASELINE, 2), 2);
+        assert_eq!(limiter.semaphore.available_permits(), 2);
+    }
+
+    #[test]
+    fn min_above_max_starts_at_max() {
+        let limiter = aimd(10, 4);
+
+        assert_eq!(limit(&limiter), 4);
+        assert_eq!(window(&limiter, BASELINE, 4), 4);
+    }
+
+    #[tokio::test]
+    async fn shrinking_below_the_permits_in_use_forgets_them_on_release() {
+        let limiter = aimd(1, 8);
+        for _ in 0..3 {
+            window(&limiter, BASELINE, 0);
+        }
+        let mut permits = Vec::new();
+        for _ in 0..8 {
+            permits.push(limiter.acquire().await.unwrap());
+        }
+
+        // every permit is held, none can be forgotten right away
+        for _ in 0..8 {
+            limiter.record(BASELINE, true);
+        }
+        assert_eq!(limit(&limiter), 4);
+        assert_eq!(limiter.lock().debt, 4);
+
+        permits.truncate(4);
+        assert_eq!(limiter.semaphore.available_permits(), 0);
+        assert_eq!(limiter.lock().debt, 0);
+
+        permits.clear();
+        assert_eq!(limiter.semaphore.available_permits(), 4);
+        assert_eq!(limiter.lock().in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn growing_repays_the_debt_first() {
+        let limiter = aimd(1, 8);
+        for _ in 0..3 {
+            window(&limiter, BASELINE, 0);
+        }
+        let mut permits = Vec::new();
+        for _ in 0..8 {
+            permits.push(limiter.acquire().await.unwrap());
+        }
+        for _ in 0..8 {
+            limiter.record(BASELINE, true);
+        }
+        assert_eq!(limiter.lock().debt, 4);
+
+        assert_eq!(window(&limiter, BASELINE, 0), 5);
+        assert_eq!(limiter.lock().debt, 3);
+        assert_eq!(limiter.semaphore.available_permits(), 0);
+
+        permits.clear();
+        assert_eq!(limiter.semaphore.available_permits(), 5);
+    }
+}
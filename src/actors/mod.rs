@@ -7,18 +7,41 @@ pub use executor::*;
 mod acker;
 pub use acker::*;
 
-use crate::TestData;
+mod config;
+pub use config::*;
 
-use pulsar::consumer::Message;
+mod retry;
+pub use retry::*;
 
-pub enum ExecutorCommand {
-  Process { msg: Message<TestData> },
-}
+mod limiter;
+pub use limiter::*;
+
+use pulsar::message::proto::MessageIdData;
+use std::time::Instant;
+
+use tokio::sync::mpsc;
+
+use crate::{handler::HandlerError, message::Message};
 
-pub enum AckerCommand {
-  Ack { msg: Message<TestData> },
-  Nack { msg: Message<TestData> },
+/// Channel back to the receiver that delivered a message, acks must go through its consumer
+pub type AckSender = mpsc::UnboundedSender<ConsumerAck>;
+
+pub enum ExecutorCommand<T> {
+  Process { msg: Message<T>, ack_tx: AckSender },
 }
 
+pub enum AckerCommand<T> {
+  /// `handled_at` is when the handler returned, to measure the time until the broker ack
+  Ack { msg: Message<T>, ack_tx: AckSender, handled_at: Instant },
+  /// the acker applies the retry policy to `error` before nacking
+  Nack { msg: Message<T>, ack_tx: AckSender, error: HandlerError },
+}
 
-pub use receiver::Receiver;
+/// Ack or nack resolved by the acker, applied by the receiver owning the consumer
+pub enum ConsumerAck {
+  Ack { topic: String, message_id: MessageIdData },
+  /// acks buffered by the acker, flushed together. `handled_at` is when the handler
+  /// returned for each of `message_ids`, in the same order
+  AckAll { topic: String, message_ids: Vec<MessageIdData>, handled_at: Vec<Instant> },
+  Nack { topic: String, message_id: MessageIdData },
+}
@@ -1,63 +1,522 @@
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    marker::PhantomData,
+    time::Instant,
+};
 
-use pulsar::{Consumer, Pulsar, SubType, TokioExecutor};
+use pulsar::{message::proto::MessageIdData, DeserializeMessage, SubType};
 use tokio::sync::mpsc;
+use tracing::{debug, error, info, info_span};
 
-use crate::TestData;
+use crate::{
+    broker::{Broker, ReceiveBackoff, Subscription},
+    error::{Error, Result},
+    latency::{Latencies, Stage},
+    metrics::Metrics,
+    shutdown::Shutdown,
+};
 
-use super::ExecutorCommand;
-use futures::{stream::FuturesUnordered, StreamExt, TryStreamExt};
+use super::{AckMode, AckSender, ConsumerAck, ExecutorCommand, PipelineConfig, RetryMode};
 
 /// Actor responsible to read data from a topic
 /// 
 /// creating a channel and returning the rx channel to be use by the executor actor
 /// 
-/// send the data to the transmiter channel
+/// send the data to the transmiter channel, every message carries the receiver ack
+/// channel so acks and nacks come back to the consumer that delivered it
 /// 
-/// TODO IMPROVE ABSTRACTION
-pub struct Receiver {
-  pulsar_consumer: Consumer<TestData, TokioExecutor>,
-  executor_tx: mpsc::Sender<ExecutorCommand>
-}
-
-impl Receiver {
-    pub async fn new(pulsar_client: &Pulsar<TokioExecutor>, topic: String, executor_tx: mpsc::Sender<ExecutorCommand>) -> Self {
-        let pulsar_consumer: Consumer<TestData, _> = pulsar_client
-        .consumer()
-        .with_topic(&topic)
-        .with_consumer_name("test_consumer")
-        .with_subscription_type(SubType::KeyShared)
-        .with_subscription("test_subscription")
-        .with_unacked_message_resend_delay(Some(Duration::from_secs(60)))
-        .build()
-        .await
-        .unwrap(); // to do handle errors
+/// generic over the broker `Subscription`, so the pipeline also runs on the in-memory broker
+pub struct Receiver<T, S> {
+    subscription: S,
+    // set in cumulative ack mode
+    cumulative: Option<CumulativeAcks>,
+    executor_tx: mpsc::Sender<ExecutorCommand<T>>,
+    ack_tx: AckSender,
+    ack_rx: mpsc::UnboundedReceiver<ConsumerAck>,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T, S> Receiver<T, S>
+where
+    T: DeserializeMessage + Send + 'static,
+    S: Subscription<T>,
+{
+    pub async fn new<B: Broker<Subscription<T> = S>>(
+        broker: &B,
+        topic: String,
+        config: &PipelineConfig,
+        executor_tx: mpsc::Sender<ExecutorCommand<T>>,
+    ) -> Result<Self> {
+        let cumulative = match (config.ack_mode, config.subscription_type) {
+            (AckMode::Individual, _) => None,
+            (AckMode::Cumulative, SubType::Exclusive | SubType::Failover) => Some(CumulativeAcks::default()),
+            (AckMode::Cumulative, sub_type) => {
+                return Err(Error::Config(format!(
+                    "cumulative acks need an exclusive or failover subscription, not {:?}",
+                    sub_type
+                )))
+            }
+        };
+
+        // a nack held longer than the resend delay would race pulsar's own redelivery, which
+        // does not count as an attempt, and the message would be handled twice
+        if let (RetryMode::Nack, Some(resend_delay)) = (config.retry.mode, config.unacked_resend_delay) {
+            let longest = config.retry.longest_delay();
+            if longest >= resend_delay {
+                return Err(Error::Config(format!(
+                    "retry backoff up to {:?} (max delay plus jitter) must stay below the unacked resend delay {:?}",
+                    longest, resend_delay
+                )));
+            }
+        }
+
+        let subscription = broker.subscribe::<T>(&config.subscribe_options(vec![topic])).await?;
+
+        Ok(Self::from_subscription(subscription, cumulative, executor_tx))
+    }
+
+    /// Receiver of the `<topic>-RETRY` retry letters, `None` unless the retry policy uses them
+    ///
+    /// feed it to the executor of `topic` so retried messages run through the same handler.
+    /// Its subscription is Shared, so acks are always individual
+    pub async fn retry<B: Broker<Subscription<T> = S>>(
+        broker: &B,
+        topic: &str,
+        config: &PipelineConfig,
+        executor_tx: mpsc::Sender<ExecutorCommand<T>>,
+    ) -> Result<Option<Self>> {
+        let Some(options) = config.retry_subscribe_options(topic) else {
+            return Ok(None);
+        };
+        let subscription = broker.subscribe::<T>(&options).await?;
+
+        Ok(Some(Self::from_subscription(subscription, None, executor_tx)))
+    }
+
+    fn from_subscription(
+        subscription: S,
+        cumulative: Option<CumulativeAcks>,
+        executor_tx: mpsc::Sender<ExecutorCommand<T>>,
+    ) -> Self {
+        // unbounded: there is at most one ack per in-flight message, and a bounded channel
+        // could deadlock with the receiver blocked on a full executor channel
+        let (ack_tx, ack_rx) = mpsc::unbounded_channel();
 
         Self {
-            pulsar_consumer,
-            executor_tx
+            subscription,
+            cumulative,
+            executor_tx,
+            ack_tx,
+            ack_rx,
+            _phantom: PhantomData,
         }
     }
 
-    /// Consume will consume messages from pulsar indefinetly
-    pub async fn consume(&mut self) {
-      loop {
-        while let pulsar_msg = self.pulsar_consumer.try_next().await {
-            match pulsar_msg {
-                Ok(Some(pulsar_msg)) => self.executor_tx
-                    .send(ExecutorCommand::Process { msg: pulsar_msg })
-                    .await
-                    .expect("to send"),
+    /// Consume will consume messages from the broker until shutdown is requested
+    ///
+    /// acks sent back by the acker are applied in between reads. Broker errors are logged
+    /// and retried after a capped backoff. It returns an error if the executor is gone or the
+    /// consumer stream ended before shutdown was requested
+    pub async fn consume(&mut self, mut shutdown: Shutdown) -> Result<()> {
+        let mut backoff = ReceiveBackoff::default();
+        loop {
+            let msg = tokio::select! {
+                _ = shutdown.recv() => {
+                    info!("receiver stop consuming");
+                    return Ok(());
+                }
+                Some(ack) = self.ack_rx.recv() => {
+                    apply_ack(&mut self.subscription, &mut self.cumulative, ack).await;
+                    continue;
+                }
+                msg = self.subscription.next() => msg,
+            };
+
+            match msg {
+                Ok(Some(msg)) => {
+                    backoff.reset();
+                    Metrics::global().received.with_label_values(&[&msg.topic]).inc();
+                    Latencies::global().received(&msg);
+                    info_span!(parent: msg.span(), "receive").in_scope(|| debug!("message received"));
+                    if let Some(cumulative) = &mut self.cumulative {
+                        cumulative.delivered(&msg.topic, &msg.message_id);
+                    }
+
+                    // counted before sending so the executor never decrements it first
+                    let queue_depth = &Metrics::global().executor_queue_depth;
+                    queue_depth.inc();
+                    let cmd = ExecutorCommand::Process {
+                        msg,
+                        ack_tx: self.ack_tx.clone(),
+                    };
+                    // the executor stops reading on shutdown, a send blocked on its full channel
+                    // or failing on its closed one is then the normal way out, the message is
+                    // redelivered by the broker
+                    let sent = tokio::select! {
+                        sent = self.executor_tx.send(cmd) => sent.is_ok(),
+                        _ = shutdown.recv() => false,
+                    };
+                    if !sent {
+                        queue_depth.dec();
+                        if shutdown.is_requested() {
+                            info!("receiver stop consuming");
+                            return Ok(());
+                        }
+                        return Err(Error::ChannelClosed("executor"));
+                    }
+                }
                 Ok(None) => {
-                    println!("nothing to poll");
+                    if shutdown.is_requested() {
+                        info!("receiver stop consuming");
+                        return Ok(());
+                    }
+                    return Err(Error::ConsumerClosed);
                 }
                 Err(e) => {
-                    eprintln!("Error consuming message: {:?}, retrying later...", e);
-                    break;
+                    if !backoff.wait(&e, &mut shutdown).await {
+                        info!("receiver stop consuming");
+                        return Ok(());
+                    }
                 }
             }
         }
     }
+
+    /// Applies the acks still on their way then closes the subscription consumer,
+    /// unacked messages are redelivered to other consumers
+    ///
+    /// should be called once the executor and the acker are done, it waits until
+    /// every in-flight message dropped its ack channel
+    pub async fn close(self) -> Result<()> {
+        let Self {
+            mut subscription,
+            mut cumulative,
+            ack_tx,
+            mut ack_rx,
+            ..
+        } = self;
+
+        drop(ack_tx);
+        while let Some(ack) = ack_rx.recv().await {
+            apply_ack(&mut subscription, &mut cumulative, ack).await;
+        }
+
+        subscription.close().await
+    }
+}
+
+async fn apply_ack<T, S: Subscription<T>>(subscription: &mut S, cumulative: &mut Option<CumulativeAcks>, ack: ConsumerAck) {
+    let metrics = Metrics::global();
+    match &ack {
+        ConsumerAck::Ack { topic, .. } => metrics.acked.with_label_values(&[topic]).inc(),
+        ConsumerAck::AckAll { topic, message_ids, .. } => metrics
+            .acked
+            .with_label_values(&[topic])
+            .inc_by(message_ids.len() as u64),
+        ConsumerAck::Nack { topic, .. } => metrics.nacked.with_label_values(&[topic]).inc(),
+    }
+
+    // handler verdicts of the messages acked on the broker by this ack, acks of dead
+    // lettered or discarded messages have none
+    let (topic, result, handled_at) = match (ack, cumulative) {
+        (ConsumerAck::Nack { topic, message_id }, _) => {
+            let result = subscription.nack(&topic, message_id).await;
+            (topic, result, Vec::new())
+        }
+        (ConsumerAck::Ack { topic, message_id }, None) => {
+            let result = subscription.ack(&topic, message_id).await;
+            (topic, result, Vec::new())
+        }
+        (ConsumerAck::AckAll { topic, message_ids, handled_at }, None) => {
+            let result = subscription.ack_all(&topic, message_ids).await;
+            (topic, result, handled_at)
+        }
+        (ConsumerAck::Ack { topic, message_id }, Some(cumulative)) => {
+            let acked = cumulative.acked(&topic, [(message_id, None)]);
+            cumulative_ack(subscription, topic, acked).await
+        }
+        (ConsumerAck::AckAll { topic, message_ids, handled_at }, Some(cumulative)) => {
+            let acks = message_ids.into_iter().zip(handled_at.into_iter().map(Some));
+            let acked = cumulative.acked(&topic, acks);
+            cumulative_ack(subscription, topic, acked).await
+        }
+    };
+
+    match result {
+        Ok(()) => {
+            let now = Instant::now();
+            for handled_at in handled_at {
+                Latencies::global().record(&topic, Stage::HandledToAcked, now - handled_at);
+            }
+        }
+        Err(e) => error!(error = %e, "applying ack failed"),
+    }
+}
+
+async fn cumulative_ack<T, S: Subscription<T>>(
+    subscription: &mut S,
+    topic: String,
+    acked: Option<(MessageIdData, Vec<Instant>)>,
+) -> (String, Result<()>, Vec<Instant>) {
+    match acked {
+        Some((message_id, handled_at)) => {
+            let result = subscription.cumulative_ack(&topic, message_id).await;
+            (topic, result, handled_at)
+        }
+        None => (topic, Ok(()), Vec::new()),
+    }
+}
+
+/// Position of a message in its topic (partition): ledger, entry and batch index
+type Position = (u64, u64, i32);
+
+fn position(message_id: &MessageIdData) -> Position {
+    (message_id.ledger_id, message_id.entry_id, message_id.batch_index.unwrap_or(-1))
+}
+
+/// Turns acks into cumulative acks
+///
+/// a cumulative ack also acks every message delivered before, so it only goes up to the
+/// last acked message preceding the oldest message still in-flight. Nacked messages stay
+/// in-flight until they are redelivered and acked
+#[derive(Default)]
+struct CumulativeAcks {
+    topics: HashMap<String, TopicAcks>,
+}
+
+#[derive(Default)]
+struct TopicAcks {
+    in_flight: BTreeSet<Position>,
+    // acked but not covered by a cumulative ack yet, with when the handler returned
+    acked: BTreeMap<Position, (MessageIdData, Option<Instant>)>,
+}
+
+impl CumulativeAcks {
+    fn delivered(&mut self, topic: &str, message_id: &MessageIdData) {
+        self.topics
+            .entry(topic.to_string())
+            .or_default()
+            .in_flight
+            .insert(position(message_id));
+    }
+
+    /// Records the acks with when their handler returned, if known
+    ///
+    /// returns the message to cumulatively ack up to, if the acks moved it, and when the
+    /// handler returned for the messages that cumulative ack covers
+    fn acked(
+        &mut self,
+        topic: &str,
+        acks: impl IntoIterator<Item = (MessageIdData, Option<Instant>)>,
+    ) -> Option<(MessageIdData, Vec<Instant>)> {
+        let topic_acks = self.topics.get_mut(topic)?;
+
+        for (message_id, handled_at) in acks {
+            let position = position(&message_id);
+            if topic_acks.in_flight.remove(&position) {
+                topic_acks.acked.insert(position, (message_id, handled_at));
+            }
+        }
+
+        let (&up_to, (message_id, _)) = match topic_acks.in_flight.first() {
+            Some(oldest_in_flight) => topic_acks.acked.range(..*oldest_in_flight).next_back()?,
+            None => topic_acks.acked.last_key_value()?,
+        };
+        let message_id = message_id.clone();
+
+        let pending = topic_acks.acked.split_off(&(up_to.0, up_to.1, up_to.2 + 1));
+        let covered = std::mem::replace(&mut topic_acks.acked, pending);
+        let handled_at = covered.into_values().filter_map(|(_, handled_at)| handled_at).collect();
+        Some((message_id, handled_at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{message::Message, shutdown, TestData};
+
+    use super::*;
+
+    const TOPIC: &str = "cumulative";
+
+    /// Fails `errors` reads, then its stream ends
+    struct Failing {
+        errors: usize,
+        reads: usize,
+    }
+
+    impl Subscription<TestData> for Failing {
+        async fn next(&mut self) -> Result<Option<Message<TestData>>> {
+            self.reads += 1;
+            if self.reads <= self.errors {
+                return Err(Error::Consume(pulsar::Error::Custom("broken".to_string())));
+            }
+            Ok(None)
+        }
+
+        async fn ack(&mut self, _topic: &str, _message_id: MessageIdData) -> Result<()> {
+            Ok(())
+        }
+
+        async fn ack_all(&mut self, _topic: &str, _message_ids: Vec<MessageIdData>) -> Result<()> {
+            Ok(())
+        }
+
+        async fn cumulative_ack(&mut self, _topic: &str, _message_id: MessageIdData) -> Result<()> {
+            Ok(())
+        }
+
+        async fn nack(&mut self, _topic: &str, _message_id: MessageIdData) -> Result<()> {
+            Ok(())
+        }
+
+        async fn close(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn failing(errors: usize) -> Receiver<TestData, Failing> {
+        let (executor_tx, _) = mpsc::channel(1);
+        Receiver::from_subscription(Failing { errors, reads: 0 }, None, executor_tx)
+    }
+
+    #[tokio::test]
+    async fn ended_stream_stops_consuming_after_backing_off_errors() {
+        let mut receiver = failing(2);
+        let (_trigger, shutdown) = shutdown::channel();
+
+        let started = Instant::now();
+        let consumed = receiver.consume(shutdown).await;
+
+        assert!(matches!(consumed, Err(Error::ConsumerClosed)));
+        assert_eq!(receiver.subscription.reads, 3);
+        // 100ms then 200ms between the failed reads
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn shutdown_interrupts_the_backoff() {
+        let mut receiver = failing(usize::MAX);
+        let (trigger, shutdown) = shutdown::channel();
+
+        let consume = tokio::spawn(async move {
+            let consumed = receiver.consume(shutdown).await;
+            (consumed, receiver.subscription.reads)
+        });
+        // past the first pauses, into a longer one
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let stopping = Instant::now();
+        trigger.trigger();
+        let (consumed, reads) = consume.await.unwrap();
+
+        assert!(consumed.is_ok());
+        assert!(stopping.elapsed() < Duration::from_millis(200));
+        // 100ms, 200ms then 400ms pauses, no spinning in between
+        assert_eq!(reads, 3);
+    }
+
+    fn id(entry: u64) -> MessageIdData {
+        MessageIdData {
+            ledger_id: 1,
+            entry_id: entry,
+            ..Default::default()
+        }
+    }
+
+    fn delivered(entries: impl IntoIterator<Item = u64>) -> CumulativeAcks {
+        let mut cumulative = CumulativeAcks::default();
+        for entry in entries {
+            cumulative.delivered(TOPIC, &id(entry));
+        }
+        cumulative
+    }
+
+    fn ack(cumulative: &mut CumulativeAcks, entries: impl IntoIterator<Item = u64>) -> Option<u64> {
+        let acks = entries.into_iter().map(|entry| (id(entry), None));
+        cumulative.acked(TOPIC, acks).map(|(message_id, _)| message_id.entry_id)
+    }
+
+    #[test]
+    fn acks_up_to_the_oldest_in_flight() {
+        let mut cumulative = delivered(0..5);
+
+        assert_eq!(ack(&mut cumulative, [0, 1]), Some(1));
+        assert_eq!(ack(&mut cumulative, [2, 3, 4]), Some(4));
+        // nothing left to move
+        assert_eq!(ack(&mut cumulative, []), None);
+    }
+
+    #[test]
+    fn gap_holds_the_cumulative_ack_back() {
+        let mut cumulative = delivered(0..5);
+
+        assert_eq!(ack(&mut cumulative, [0, 2, 3]), Some(0));
+        assert_eq!(ack(&mut cumulative, [4]), None);
+        // filling the gap covers everything acked after it
+        assert_eq!(ack(&mut cumulative, [1]), Some(4));
+    }
+
+    #[test]
+    fn out_of_order_completion() {
+        let mut cumulative = delivered(0..4);
+
+        assert_eq!(ack(&mut cumulative, [3]), None);
+        assert_eq!(ack(&mut cumulative, [2]), None);
+        assert_eq!(ack(&mut cumulative, [0]), Some(0));
+        assert_eq!(ack(&mut cumulative, [1]), Some(3));
+    }
+
+    #[test]
+    fn nacked_message_stays_in_flight_until_redelivered_and_acked() {
+        let mut cumulative = delivered(0..4);
+
+        // 1 is nacked, so never acked here
+        assert_eq!(ack(&mut cumulative, [0, 2, 3]), Some(0));
+
+        // the redelivery does not move anything either
+        cumulative.delivered(TOPIC, &id(1));
+        assert_eq!(ack(&mut cumulative, []), None);
+
+        assert_eq!(ack(&mut cumulative, [1]), Some(3));
+    }
+
+    #[test]
+    fn batch_indexes_are_ordered_within_an_entry() {
+        let batched = |index| MessageIdData {
+            batch_index: Some(index),
+            ..id(7)
+        };
+        let mut cumulative = CumulativeAcks::default();
+        for index in 0..3 {
+            cumulative.delivered(TOPIC, &batched(index));
+        }
+
+        let acked = cumulative.acked(TOPIC, [(batched(2), None), (batched(0), None)]);
+        assert_eq!(acked.map(|(message_id, _)| message_id.batch_index), Some(Some(0)));
+        let acked = cumulative.acked(TOPIC, [(batched(1), None)]);
+        assert_eq!(acked.map(|(message_id, _)| message_id.batch_index), Some(Some(2)));
+    }
+
+    #[test]
+    fn handled_times_of_the_covered_messages_are_returned() {
+        let mut cumulative = delivered(0..3);
+        let handled_at = Instant::now();
+
+        let acked = cumulative.acked(TOPIC, [(id(1), Some(handled_at)), (id(2), Some(handled_at))]);
+        assert!(acked.is_none());
+
+        let (message_id, handled) = cumulative.acked(TOPIC, [(id(0), None)]).unwrap();
+        assert_eq!(message_id.entry_id, 2);
+        // the ack without a handled time is covered but not timed
+        assert_eq!(handled, [handled_at, handled_at]);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn unknown_topic_is_ignored() {
+        let mut cumulative = delivered(0..1);
+
+        assert!(cumulative.acked("other", [(id(0), None)]).is_none());
+    }
+}
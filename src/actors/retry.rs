@@ -0,0 +1,232 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::handler::HandlerError;
+
+/// Exponential backoff applied by the acker before a failed message is nacked
+///
+/// the delay after the nth failed attempt is `base_delay * multiplier^(n - 1)`, capped
+/// to `max_delay` and spread by `jitter`. Once `max_attempts` deliveries failed, or right
+/// away for a `HandlerError::Permanent`, the message goes to `exhausted_action`
+///
+/// `mode` picks how the message comes back after the backoff: a delayed nack, or a retry
+/// letter published to `<topic>-RETRY`
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub mode: RetryMode,
+    /// deliveries before giving up on a message, 0 retries forever
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub multiplier: f64,
+    pub max_delay: Duration,
+    /// fraction of the delay added or removed at random, between 0.0 and 1.0
+    pub jitter: f64,
+    pub exhausted_action: ExhaustedAction,
+}
+
+/// How a message is delivered again after a retryable failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryMode {
+    /// nack it once the backoff elapsed, it stays unacked in the subscription meanwhile
+    Nack,
+    /// ack it and republish it to `<topic>-RETRY`, delivered once the backoff elapsed
+    RetryLetter,
+}
+
+impl FromStr for RetryMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "nack" => Ok(RetryMode::Nack),
+            "retry_letter" => Ok(RetryMode::RetryLetter),
+            _ => Err(format!("unknown retry mode {:?}, expected nack or retry_letter", s)),
+        }
+    }
+}
+
+/// What happens to a message that will not be retried anymore
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExhaustedAction {
+    /// ack it so it is never delivered again, the failure is only logged
+    Discard,
+    /// republish it to the dead letter topic, then ack it
+    DeadLetter,
+}
+
+impl FromStr for ExhaustedAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "discard" => Ok(ExhaustedAction::Discard),
+            "dead_letter" => Ok(ExhaustedAction::DeadLetter),
+            _ => Err(format!("unknown exhausted action {:?}, expected discard or dead_letter", s)),
+        }
+    }
+}
+
+/// Outcome of a failed attempt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetryDecision {
+    /// deliver the message again once the delay elapsed, according to the retry mode
+    Retry(Duration),
+    Exhausted(ExhaustedAction),
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            mode: RetryMode::Nack,
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            // below the default unacked resend delay once the jitter is added
+            max_delay: Duration::from_secs(30),
+            jitter: 0.2,
+            exhausted_action: ExhaustedAction::DeadLetter,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Decides what to do after `attempt` (1 for the first delivery) failed with `error`
+    pub fn decide(&self, attempt: u32, error: &HandlerError) -> RetryDecision {
+        let exhausted = self.max_attempts > 0 && attempt >= self.max_attempts;
+
+        match error {
+            HandlerError::Permanent(_) => RetryDecision::Exhausted(self.exhausted_action),
+            HandlerError::Retryable(_) if exhausted => RetryDecision::Exhausted(self.exhausted_action),
+            HandlerError::Retryable(_) => RetryDecision::Retry(self.delay(attempt)),
+        }
+    }
+
+    /// Upper bound of `delay`, `max_delay` with the whole jitter added
+    pub fn longest_delay(&self) -> Duration {
+        self.max_delay.mul_f64(1.0 + self.jitter.clamp(0.0, 1.0))
+    }
+
+    /// Backoff after `attempt` failed, jitter included
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.base_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        let delay = delay.min(self.max_delay.as_secs_f64());
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        // random factor in [1 - jitter, 1 + jitter]
+        let factor = 1.0 + jitter * (2.0 * random_unit() - 1.0);
+
+        Duration::try_from_secs_f64(delay * factor).unwrap_or(self.max_delay)
+    }
+}
+
+/// Random number in [0, 1) without pulling a rand dependency, std seeds `RandomState` randomly
+pub(crate) fn random_unit() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: u32, jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(1),
+            jitter,
+            ..RetryPolicy::default()
+        }
+    }
+
+    fn retryable() -> HandlerError {
+        HandlerError::Retryable("down".to_string())
+    }
+
+    #[test]
+    fn retries_until_max_attempts() {
+        let policy = policy(3, 0.0);
+
+        assert_eq!(policy.decide(1, &retryable()), RetryDecision::Retry(Duration::from_millis(100)));
+        assert_eq!(policy.decide(2, &retryable()), RetryDecision::Retry(Duration::from_millis(200)));
+        assert_eq!(
+            policy.decide(3, &retryable()),
+            RetryDecision::Exhausted(ExhaustedAction::DeadLetter)
+        );
+        assert_eq!(
+            policy.decide(4, &retryable()),
+            RetryDecision::Exhausted(ExhaustedAction::DeadLetter)
+        );
+    }
+
+    #[test]
+    fn zero_max_attempts_retries_forever() {
+        let policy = policy(0, 0.0);
+
+        for attempt in [1, 10, 1000, u32::MAX] {
+            assert!(matches!(policy.decide(attempt, &retryable()), RetryDecision::Retry(_)));
+        }
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn permanent_errors_are_never_retried() {
+        let policy = RetryPolicy {
+            exhausted_action: ExhaustedAction::Discard,
+            ..policy(0, 0.0)
+        };
+
+        assert_eq!(
+            policy.decide(1, &HandlerError::Permanent("bad payload".to_string())),
+            RetryDecision::Exhausted(ExhaustedAction::Discard)
+        );
+    }
+
+    #[test]
+    fn delay_grows_up_to_max_delay() {
+        let policy = policy(0, 0.0);
+
+        let delays: Vec<Duration> = (1..=6).map(|attempt| policy.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let jittered = policy(0, 0.2);
+
+        for attempt in 1..=6 {
+            let base = policy(0, 0.0).delay(attempt);
+            for _ in 0..200 {
+                let delay = jittered.delay(attempt);
+                // a microsecond of slack for the float rounding
+                let (low, high) = (base.mul_f64(0.8), base.mul_f64(1.2));
+                assert!(
+                    delay + Duration::from_micros(1) >= low && delay <= high + Duration::from_micros(1),
+                    "{:?} for {:?}",
+                    delay,
+                    base
+                );
+                assert!(delay <= jittered.longest_delay());
+            }
+        }
+
+        // out of range jitters are clamped
+        let wild = policy(0, 5.0);
+        for _ in 0..200 {
+            assert!(wild.delay(1) <= Duration::from_millis(200));
+        }
+        assert_eq!(wild.longest_delay(), Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use pulsar::DeserializeMessage;
+use tokio::time::timeout;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::{
+    actors::{ExhaustedAction, RetryDecision, RetryPolicy},
+    broker::{Broker, ReceiveBackoff, Subscription},
+    dead_letter::DeadLetter,
+    error::{Error, Result},
+    handler::{BatchHandler, HandlerResult},
+    latency::{Latencies, Stage},
+    message::Message,
+    metrics::Metrics,
+    shutdown::Shutdown,
+};
+
+/// Consumer that reads messages in batches and hands each batch to a `BatchHandler`
+///
+/// a batch is closed once it holds `batch_size` messages or once `batch_timeout` elapses,
+/// whichever comes first. Every message is then acked or nacked according to the verdict
+/// returned by the handler. Failed messages follow the `RetryPolicy` attempts limit and
+/// exhausted action, but they are nacked right away: there is no backoff nor retry letter
+/// in batch mode
+pub struct BatchConsumer<T, S, H, B> {
+    subscription: S,
+    handler: H,
+    batch_size: usize,
+    batch_timeout: Duration,
+    retry: RetryPolicy,
+    dead_letter: DeadLetter<B>,
+    backoff: ReceiveBackoff,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T, S, H, B> BatchConsumer<T, S, H, B>
+where
+    T: DeserializeMessage + Send + 'static,
+    S: Subscription<T>,
+    H: BatchHandler<T>,
+    B: Broker,
+{
+    pub fn new(
+        subscription: S,
+        handler: H,
+        batch_size: usize,
+        batch_timeout: Duration,
+        retry: RetryPolicy,
+        dead_letter: DeadLetter<B>,
+    ) -> Self {
+        Self {
+            subscription,
+            handler,
+            batch_size,
+            batch_timeout,
+            retry,
+            dead_letter,
+            backoff: ReceiveBackoff::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Run will consume and process batches until shutdown is requested or the consumer stream
+    /// ends
+    ///
+    /// the batch being filled when shutdown arrives is still processed and acked
+    pub async fn run(&mut self, mut shutdown: Shutdown) {
+        loop {
+            let before = Instant::now();
+
+            let (batch, stop) = self.next_batch(&mut shutdown).await;
+
+            debug!(len = batch.len(), "processing batch");
+
+            let tasks_processed = batch.len();
+            let results = self.handler.handle_batch(batch).await;
+            let handled_at = Instant::now();
+            if results.len() != tasks_processed {
+                error!(
+                    returned = results.len(),
+                    messages = tasks_processed,
+                    "batch handler dropped messages, the broker will redeliver them"
+                );
+            }
+
+            for (msg, verdict) in results {
+                Latencies::global().record(&msg.topic, Stage::ReceiveToHandled, handled_at - msg.received_at());
+                let span = info_span!(parent: msg.span(), "ack");
+                self.resolve(msg, verdict, handled_at).instrument(span).await;
+            }
+
+            let elapsed = before.elapsed();
+            if tasks_processed > 0 {
+                info!(
+                    tasks_processed,
+                    ?elapsed,
+                    avg_per_message = ?(elapsed / tasks_processed as u32),
+                    "batch processed"
+                );
+            }
+
+            if stop {
+                return;
+            }
+        }
+    }
+
+    /// Acks, nacks or dead letters a message according to its verdict and the retry policy
+    async fn resolve(&mut self, msg: Message<T>, verdict: HandlerResult, handled_at: Instant) {
+        let error = match verdict {
+            Ok(()) => {
+                Metrics::global().acked.with_label_values(&[&msg.topic]).inc();
+                debug!("message acked");
+                if let Err(e) = self.subscription.ack(&msg.topic, msg.message_id).await {
+                    error!(error = %e, "ack failed");
+                }
+                Latencies::global().record(&msg.topic, Stage::HandledToAcked, handled_at.elapsed());
+                return;
+            }
+            Err(error) => error,
+        };
+
+        let attempt = msg.attempt();
+        warn!(attempt, %error, "handler failed");
+
+        let ack = match self.retry.decide(attempt, &error) {
+            RetryDecision::Retry(_) => false,
+            RetryDecision::Exhausted(ExhaustedAction::Discard) => {
+                warn!(attempt, "giving up on message, discarding it");
+                Metrics::global().discarded.with_label_values(&[msg.origin_topic()]).inc();
+                true
+            }
+            RetryDecision::Exhausted(ExhaustedAction::DeadLetter) => {
+                match self.dead_letter.publish(&msg, &error, attempt).await {
+                    Ok(()) => {
+                        warn!(
+                            attempt,
+                            dead_letter_topic = %self.dead_letter.topic_for(msg.origin_topic()),
+                            "message dead lettered"
+                        );
+                        Metrics::global().dead_lettered.with_label_values(&[msg.origin_topic()]).inc();
+                        true
+                    }
+                    Err(e) => {
+                        error!(error = %e, "dead lettering failed, nacking");
+                        false
+                    }
+                }
+            }
+        };
+
+        let counter = if ack { &Metrics::global().acked } else { &Metrics::global().nacked };
+        counter.with_label_values(&[&msg.topic]).inc();
+
+        let result = if ack {
+            self.subscription.ack(&msg.topic, msg.message_id).await
+        } else {
+            self.subscription.nack(&msg.topic, msg.message_id).await
+        };
+        if let Err(e) = result {
+            error!(error = %e, ack, "ack or nack failed");
+        }
+    }
+
+    /// Close the subscription consumer, unacked messages are redelivered to other consumers
+    pub async fn close(mut self) -> Result<()> {
+        self.subscription.close().await
+    }
+
+    /// Reads messages until the batch is full or the batch timeout elapses
+    ///
+    /// returns early, flagging it, if shutdown is requested or the consumer stream ended. A
+    /// failed read closes the batch, an empty one is only returned after the receive backoff
+    async fn next_batch(&mut self, shutdown: &mut Shutdown) -> (Vec<Message<T>>, bool) {
+        let mut batch = Vec::with_capacity(self.batch_size);
+        let mut failed = None;
+        let mut ended = false;
+        let batch_size = self.batch_size;
+        let subscription = &mut self.subscription;
+        let backoff = &mut self.backoff;
+
+        let fill = timeout(self.batch_timeout, async {
+            while batch.len() < batch_size {
+                match subscription.next().await {
+                    Ok(Some(msg)) => {
+                        backoff.reset();
+                        Metrics::global().received.with_label_values(&[&msg.topic]).inc();
+                        Latencies::global().received(&msg);
+                        info_span!(parent: msg.span(), "receive").in_scope(|| debug!("message received"));
+                        batch.push(msg);
+                    }
+                    Ok(None) => {
+                        ended = true;
+                        break;
+                    }
+                    Err(e) => {
+                        failed = Some(e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        let mut stop = tokio::select! {
+            _ = fill => false,
+            _ = shutdown.recv() => true,
+        };
+
+        if ended && !stop {
+            error!(error = %Error::ConsumerClosed, "batch consumer stop consuming");
+            stop = true;
+        }
+        if let Some(e) = failed.filter(|_| !stop) {
+            if batch.is_empty() {
+                stop = !backoff.wait(&e, shutdown).await;
+            } else {
+                // the messages read so far are processed first, the next read backs off if it
+                // fails again
+                warn!(error = %e, "receive failed, retrying after the batch");
+            }
+        }
+
+        (batch, stop)
+    }
+}
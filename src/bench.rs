@@ -0,0 +1,487 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::{self, File},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    actors::{AckMode, ExecutionOrder, LimitMode},
+    config::{Config, ConsumeMode, KeyDistribution, SubscriptionType},
+    error::{Error, Result},
+    latency::LatencySummary,
+    system::SystemInfo,
+};
+
+/// Output format of the bench report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    /// the whole report as one pretty printed object
+    Json,
+    /// a row per topic and latency stage, the run fields repeated on every row
+    Csv,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ReportFormat::Json),
+            "csv" => Ok(ReportFormat::Csv),
+            _ => Err(format!("unknown report format {:?}, expected json or csv", s)),
+        }
+    }
+}
+
+/// Outcome of a bench run: what ran, how fast and where
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    /// when the run finished, seconds since the unix epoch
+    pub timestamp: u64,
+    pub scenario: Scenario,
+    pub produce: ProduceResult,
+    pub consume: ConsumeResult,
+    /// percentiles per topic and stage, in milliseconds
+    pub latencies: Vec<LatencyReport>,
+    pub system: SystemInfo,
+}
+
+/// Settings of the run that change the results
+#[derive(Debug, Clone, Serialize)]
+pub struct Scenario {
+    pub topics: Vec<String>,
+    pub messages_per_topic: usize,
+    pub producer_batch_size: u32,
+    pub partition_keys: usize,
+    pub key_distribution: KeyDistribution,
+    pub mode: ConsumeMode,
+    pub subscription_type: SubscriptionType,
+    pub max_concurrency: usize,
+    pub topic_weights: BTreeMap<String, u32>,
+    pub topic_priorities: BTreeMap<String, u8>,
+    pub low_priority_share: f64,
+    pub concurrency_mode: LimitMode,
+    pub ordering: ExecutionOrder,
+    pub compute_concurrency: usize,
+    pub batch_size: usize,
+    pub batch_timeout_ms: u64,
+    pub ack_batch_size: usize,
+    pub ack_flush_interval_ms: u64,
+    pub ack_mode: AckMode,
+}
+
+impl Scenario {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            topics: config.consumer.topics.names().to_vec(),
+            messages_per_topic: config.producer.messages_per_topic,
+            producer_batch_size: config.producer.batch_size,
+            partition_keys: config.producer.partition_keys.len(),
+            key_distribution: config.producer.key_distribution,
+            mode: config.consumer.mode,
+            subscription_type: config.consumer.subscription_type,
+            max_concurrency: config.consumer.max_concurrency,
+            topic_weights: config.consumer.topic_weights.clone(),
+            topic_priorities: config.consumer.topic_priorities.clone(),
+            low_priority_share: config.consumer.low_priority_share,
+            concurrency_mode: config.consumer.concurrency_mode,
+            ordering: config.consumer.ordering,
+            compute_concurrency: config.consumer.compute_concurrency,
+            batch_size: config.consumer.batch_size,
+            batch_timeout_ms: config.consumer.batch_timeout_ms,
+            ack_batch_size: config.consumer.ack_batch_size,
+            ack_flush_interval_ms: config.consumer.ack_flush_interval_ms,
+            ack_mode: config.consumer.ack_mode,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProduceResult {
+    /// messages confirmed by the broker
+    pub messages: usize,
+    pub elapsed_secs: f64,
+    pub messages_per_sec: f64,
+}
+
+impl ProduceResult {
+    pub fn new(messages: usize, elapsed: Duration) -> Self {
+        Self {
+            messages,
+            elapsed_secs: elapsed.as_secs_f64(),
+            messages_per_sec: per_sec(messages as u64, elapsed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConsumeResult {
+    pub acked: u64,
+    pub nacked: u64,
+    /// handler failures, a message failing then succeeding on redelivery counts once
+    pub failed: u64,
+    /// handler successes, retry letters included
+    pub succeeded: u64,
+    /// given up on, they are acked too but never succeeded
+    pub dead_lettered: u64,
+    pub discarded: u64,
+    pub elapsed_secs: f64,
+    /// acked messages per second, from the consumer start to its shutdown
+    pub messages_per_sec: f64,
+    /// false when the timeout or a signal stopped the run before every produced message
+    /// succeeded, was dead lettered or discarded
+    pub complete: bool,
+}
+
+impl ConsumeResult {
+    pub fn new(acked: u64, nacked: u64, failed: u64, elapsed: Duration, complete: bool) -> Self {
+        Self {
+            acked,
+            nacked,
+            failed,
+            succeeded: 0,
+            dead_lettered: 0,
+            discarded: 0,
+            elapsed_secs: elapsed.as_secs_f64(),
+            messages_per_sec: per_sec(acked, elapsed),
+            complete,
+        }
+    }
+
+    /// Sets how the consumed messages ended up
+    pub fn with_outcomes(mut self, succeeded: u64, dead_lettered: u64, discarded: u64) -> Self {
+        self.succeeded = succeeded;
+        self.dead_lettered = dead_lettered;
+        self.discarded = discarded;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyReport {
+    pub topic: String,
+    pub stage: &'static str,
+    pub count: u64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+impl From<LatencySummary> for LatencyReport {
+    fn from(summary: LatencySummary) -> Self {
+        Self {
+            topic: summary.topic,
+            stage: summary.stage,
+            count: summary.count,
+            p50_ms: millis(summary.p50),
+            p90_ms: millis(summary.p90),
+            p99_ms: millis(summary.p99),
+            max_ms: millis(summary.max),
+        }
+    }
+}
+
+/// Columns of the CSV report, new ones go at the end so older files keep lining up
+const CSV_HEADER: &str = "timestamp,topics,messages_per_topic,producer_batch_size,partition_keys,\
+key_distribution,mode,subscription_type,max_concurrency,batch_size,batch_timeout_ms,ack_batch_size,\
+ack_flush_interval_ms,ack_mode,produced,produce_secs,produce_per_sec,acked,nacked,failed,consume_secs,\
+consume_per_sec,complete,topic,stage,count,p50_ms,p90_ms,p99_ms,max_ms,os,kernel_version,rust_version,\
+total_memory_gb,total_swap_gb,cpu_brand,cpus,tokio_workers,concurrency_mode,ordering,compute_concurrency,\
+topic_weights,topic_priorities,low_priority_share,succeeded,dead_lettered,discarded";
+
+impl BenchReport {
+    pub fn new(
+        scenario: Scenario,
+        produce: ProduceResult,
+        consume: ConsumeResult,
+        latencies: Vec<LatencySummary>,
+        system: SystemInfo,
+    ) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            scenario,
+            produce,
+            consume,
+            latencies: latencies.into_iter().map(LatencyReport::from).collect(),
+            system,
+        }
+    }
+
+    /// Writes the report to `path`, JSON overwrites the file while CSV appends to it
+    ///
+    /// a CSV file written with other columns is left alone, the rows go to the first
+    /// `<stem>-<n>.<extension>` next to it that is new or has the same columns.
+    /// Returns the path written
+    pub fn write(&self, path: &Path, format: ReportFormat) -> Result<PathBuf> {
+        let written = match format {
+            ReportFormat::Json => serde_json::to_vec_pretty(self)
+                .map_err(std::io::Error::from)
+                .and_then(|json| fs::write(path, json))
+                .map(|()| path.to_path_buf()),
+            ReportFormat::Csv => self.append_csv(path),
+        };
+
+        written.map_err(|e| Error::Report(format!("writing {}: {}", path.display(), e)))
+    }
+
+    fn append_csv(&self, path: &Path) -> std::io::Result<PathBuf> {
+        let path = csv_path(path)?;
+        let mut file = File::options().create(true).append(true).open(&path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{}", CSV_HEADER)?;
+        }
+
+        // a run without any latency recorded still gets its row
+        let latencies: Vec<Option<&LatencyReport>> = if self.latencies.is_empty() {
+            vec![None]
+        } else {
+            self.latencies.iter().map(Some).collect()
+        };
+
+        for latency in latencies {
+            let row = self.csv_row(latency).iter().map(|field| csv_field(field)).collect::<Vec<_>>();
+            writeln!(file, "{}", row.join(","))?;
+        }
+        Ok(path)
+    }
+
+    fn csv_row(&self, latency: Option<&LatencyReport>) -> Vec<String> {
+        let Self {
+            scenario,
+            produce,
+            consume,
+            system,
+            ..
+        } = self;
+        let latency = match latency {
+            Some(latency) => vec![
+                latency.topic.clone(),
+                latency.stage.to_string(),
+                latency.count.to_string(),
+                latency.p50_ms.to_string(),
+                latency.p90_ms.to_string(),
+                latency.p99_ms.to_string(),
+                latency.max_ms.to_string(),
+            ],
+            None => vec![String::new(); 7],
+        };
+
+        [
+            vec![
+                self.timestamp.to_string(),
+                scenario.topics.join(";"),
+                scenario.messages_per_topic.to_string(),
+                scenario.producer_batch_size.to_string(),
+                scenario.partition_keys.to_string(),
+                variant_name(&scenario.key_distribution),
+                variant_name(&scenario.mode),
+                variant_name(&scenario.subscription_type),
+                scenario.max_concurrency.to_string(),
+                scenario.batch_size.to_string(),
+                scenario.batch_timeout_ms.to_string(),
+                scenario.ack_batch_size.to_string(),
+                scenario.ack_flush_interval_ms.to_string(),
+                variant_name(&scenario.ack_mode),
+                produce.messages.to_string(),
+                produce.elapsed_secs.to_string(),
+                produce.messages_per_sec.to_string(),
+                consume.acked.to_string(),
+                consume.nacked.to_string(),
+                consume.failed.to_string(),
+                consume.elapsed_secs.to_string(),
+                consume.messages_per_sec.to_string(),
+                consume.complete.to_string(),
+            ],
+            latency,
+            vec![
+                system.os.clone(),
+                system.kernel_version.clone(),
+                system.rust_version.clone(),
+                system.total_memory_gb.to_string(),
+                system.total_swap_gb.to_string(),
+                system.cpu_brand.clone(),
+                system.cpus.to_string(),
+                system.tokio_workers.to_string(),
+            ],
+            vec![
+                variant_name(&scenario.concurrency_mode),
+                variant_name(&scenario.ordering),
+                scenario.compute_concurrency.to_string(),
+                map_field(&scenario.topic_weights),
+                map_field(&scenario.topic_priorities),
+                scenario.low_priority_share.to_string(),
+                consume.succeeded.to_string(),
+                consume.dead_lettered.to_string(),
+                consume.discarded.to_string(),
+            ],
+        ]
+        .concat()
+    }
+}
+
+/// `path`, or the first `<stem>-<n>.<extension>` from 2 on if its header is not `CSV_HEADER`
+fn csv_path(path: &Path) -> std::io::Result<PathBuf> {
+    for n in 1.. {
+        let candidate = match n {
+            1 => path.to_path_buf(),
+            n => numbered(path, n),
+        };
+
+        let mut header = String::new();
+        match File::open(&candidate) {
+            Ok(file) => BufReader::new(file).read_line(&mut header)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(candidate),
+            Err(e) => return Err(e),
+        };
+        let header = header.trim_end_matches(['\n', '\r']);
+        if header.is_empty() || header == CSV_HEADER {
+            return Ok(candidate);
+        }
+    }
+
+    unreachable!("the loop only ends by returning")
+}
+
+/// `dir/<stem>-<n>.<extension>` of `path`
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, n, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+/// Name of a unit enum variant as serde writes it, e.g. `round_robin`
+fn variant_name<T: serde::Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+/// `name=value` pairs separated by semicolons
+fn map_field<V: Display>(map: &BTreeMap<String, V>) -> String {
+    map.iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Quotes a CSV field holding a separator, a quote or a line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn per_sec(count: u64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        0.0
+    } else {
+        count as f64 / elapsed.as_secs_f64()
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> BenchReport {
+        let system = SystemInfo {
+            os: "linux".to_string(),
+            kernel_version: "6.1".to_string(),
+            rust_version: "1.84".to_string(),
+            total_memory_gb: 16,
+            total_swap_gb: 0,
+            cpu_brand: "Cpu \"Turbo\", 8 cores".to_string(),
+            cpus: 8,
+            tokio_workers: 8,
+        };
+
+        BenchReport::new(
+            Scenario::new(&Config::default()),
+            ProduceResult::new(10, Duration::from_secs(1)),
+            ConsumeResult::new(10, 0, 0, Duration::from_secs(2), true),
+            Vec::new(),
+            system,
+        )
+    }
+
+    /// Empty directory of the test in the temp dir
+    fn dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("poc-bench-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        fs::read_to_string(path).unwrap().lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn fields_with_separators_or_quotes_are_quoted() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a;b"), "a;b");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn rows_are_appended_under_one_header() {
+        let dir = dir("append");
+        let path = dir.join("runs.csv");
+        let report = report();
+
+        assert_eq!(report.write(&path, ReportFormat::Csv).unwrap(), path);
+        assert_eq!(report.write(&path, ReportFormat::Csv).unwrap(), path);
+
+        let lines = lines(&path);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(lines[1], lines[2]);
+        assert!(lines[1].contains(",\"Cpu \"\"Turbo\"\", 8 cores\","));
+        // the quoted field still counts as one column
+        let columns = CSV_HEADER.split(',').count();
+        let unquoted = lines[1].replace("\"Cpu \"\"Turbo\"\", 8 cores\"", "cpu");
+        assert_eq!(unquoted.split(',').count(), columns);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn other_columns_go_to_the_next_numbered_file() {
+        let dir = dir("mismatch");
+        let path = dir.join("runs.csv");
+        fs::write(&path, "timestamp,topics\n1,test\n").unwrap();
+        fs::write(dir.join("runs-2.csv"), "older,columns\n").unwrap();
+        let report = report();
+
+        let written = report.write(&path, ReportFormat::Csv).unwrap();
+        assert_eq!(written, dir.join("runs-3.csv"));
+        assert_eq!(report.write(&path, ReportFormat::Csv).unwrap(), written);
+
+        assert_eq!(lines(&path), ["timestamp,topics", "1,test"]);
+        assert_eq!(lines(&dir.join("runs-2.csv")), ["older,columns"]);
+        let lines = lines(&written);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], CSV_HEADER);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,758 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use pulsar::{
+    message::{
+        proto::{KeyValue, MessageIdData, MessageMetadata},
+        Payload,
+    },
+    producer, DeserializeMessage, Error as PulsarError, SubType,
+};
+use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
+
+use crate::{
+    error::{Error, Result},
+    message::Message,
+};
+
+use super::{Broker, SubscribeOptions, Subscription};
+
+/// In-memory `Broker` so pipelines can be tested without a pulsar cluster
+///
+/// models the pulsar behaviours the pipeline relies on:
+/// - topics are created on first use and subscriptions start at the latest message
+/// - Exclusive and Failover deliver to a single consumer, Shared round robins, KeyShared
+///   always sends a partition key to the same consumer while the consumer set is stable
+/// - a nack redelivers right away, an unacked message is redelivered after `unacked_resend_delay`
+/// - a consumer only acks or nacks the messages delivered to it, the others are left alone
+/// - closing (or dropping) a consumer redelivers its unacked messages to the remaining ones
+/// - `produce_at` delays the delivery for Shared subscriptions only
+#[derive(Clone, Default)]
+pub struct MemoryBroker {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    topics: HashMap<String, Topic>,
+    next_consumer_id: u64,
+}
+
+#[derive(Default)]
+struct Topic {
+    // the entry id is the index in this log
+    entries: Vec<Payload>,
+    subscriptions: HashMap<String, SubscriptionState>,
+}
+
+struct SubscriptionState {
+    sub_type: SubType,
+    consumers: Vec<ConsumerSlot>,
+    // entries waiting for a consumer to join
+    backlog: VecDeque<u64>,
+    unacked: HashMap<u64, Unacked>,
+    redelivery_counts: HashMap<u64, u32>,
+    // entries published with a deliver_at time not reached yet
+    delayed: usize,
+    next_round_robin: usize,
+}
+
+struct ConsumerSlot {
+    id: u64,
+    tx: mpsc::UnboundedSender<Delivery>,
+}
+
+struct Unacked {
+    consumer_id: u64,
+    delivered_at: Instant,
+}
+
+struct Delivery {
+    topic: String,
+    payload: Payload,
+    message_id: MessageIdData,
+    redelivery_count: u32,
+}
+
+impl MemoryBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Messages of the subscription not acked yet, either delayed, waiting for a consumer or in-flight
+    pub fn pending(&self, topic: &str, subscription: &str) -> usize {
+        self.lock()
+            .topics
+            .get(topic)
+            .and_then(|topic| topic.subscriptions.get(subscription))
+            .map_or(0, |sub| sub.delayed + sub.backlog.len() + sub.unacked.len())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // the state is always left consistent, a panic while holding the lock can be ignored
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Broker for MemoryBroker {
+    type Subscription<T: DeserializeMessage + Send + 'static> = MemorySubscription<T>;
+
+    async fn subscribe<T: DeserializeMessage + Send + 'static>(
+        &self,
+        options: &SubscribeOptions,
+    ) -> Result<MemorySubscription<T>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let consumer_id = {
+            let mut state = self.lock();
+
+            for topic in &options.topics {
+                let conflict = state
+                    .topics
+                    .get(topic)
+                    .and_then(|topic| topic.subscriptions.get(&options.subscription))
+                    .filter(|sub| !sub.consumers.is_empty())
+                    .and_then(|sub| {
+                        if sub.sub_type != options.subscription_type {
+                            Some("subscription type mismatch")
+                        } else if sub.sub_type == SubType::Exclusive {
+                            Some("exclusive subscription already has a consumer")
+                        } else {
+                            None
+                        }
+                    });
+
+                if let Some(reason) = conflict {
+                    return Err(Error::Build(PulsarError::Custom(format!(
+                        "cannot subscribe {} to {}: {}",
+                        options.subscription, topic, reason
+                    ))));
+                }
+            }
+
+            let consumer_id = state.next_consumer_id;
+            state.next_consumer_id += 1;
+
+            for topic_name in &options.topics {
+                let Topic {
+                    entries,
+                    subscriptions,
+                } = state.topics.entry(topic_name.clone()).or_default();
+
+                let sub = subscriptions
+                    .entry(options.subscription.clone())
+                    .or_insert_with(|| SubscriptionState::new(options.subscription_type));
+                sub.consumers.push(ConsumerSlot {
+                    id: consumer_id,
+                    tx: tx.clone(),
+                });
+
+                for entry_id in std::mem::take(&mut sub.backlog) {
+                    sub.dispatch(topic_name, entries, entry_id);
+                }
+            }
+
+            consumer_id
+        };
+
+        let resend_task = options.unacked_resend_delay.map(|delay| {
+            spawn_resend_task(
+                Arc::downgrade(&self.state),
+                options.topics.clone(),
+                options.subscription.clone(),
+                consumer_id,
+                delay,
+            )
+        });
+
+        Ok(MemorySubscription {
+            broker: self.clone(),
+            consumer_id,
+            subscription: options.subscription.clone(),
+            topics: options.topics.clone(),
+            rx,
+            resend_task,
+            closed: false,
+            _phantom: PhantomData,
+        })
+    }
+
+    async fn produce(&self, topic: &str, message: producer::Message) -> Result<()> {
+        self.publish(topic, message, None);
+        Ok(())
+    }
+
+    async fn produce_at(&self, topic: &str, message: producer::Message, deliver_at: SystemTime) -> Result<()> {
+        self.publish(topic, message, Some(deliver_at));
+        Ok(())
+    }
+}
+
+impl MemoryBroker {
+    fn publish(&self, topic: &str, message: producer::Message, deliver_at: Option<SystemTime>) {
+        let mut state = self.lock();
+        let Topic {
+            entries,
+            subscriptions,
+        } = state.topics.entry(topic.to_string()).or_default();
+
+        let entry_id = entries.len() as u64;
+        let publish_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let deliver_at_time = deliver_at
+            .and_then(|deliver_at| deliver_at.duration_since(UNIX_EPOCH).ok())
+            .map(|deliver_at| deliver_at.as_millis() as i64);
+        // negative or zero delays are delivered right away
+        let delay = deliver_at
+            .and_then(|deliver_at| deliver_at.duration_since(SystemTime::now()).ok())
+            .filter(|delay| !delay.is_zero());
+
+        entries.push(Payload {
+            metadata: MessageMetadata {
+                producer_name: "memory".to_string(),
+                sequence_id: entry_id,
+                publish_time,
+                properties: message
+                    .properties
+                    .into_iter()
+                    .map(|(key, value)| KeyValue { key, value })
+                    .collect(),
+                partition_key: message.partition_key,
+                ordering_key: message.ordering_key,
+                event_time: message.event_time,
+                deliver_at_time,
+                ..Default::default()
+            },
+            data: message.payload,
+        });
+
+        for (name, sub) in subscriptions.iter_mut() {
+            match delay {
+                Some(delay) if sub.sub_type == SubType::Shared => {
+                    sub.delayed += 1;
+                    spawn_delayed_dispatch(
+                        Arc::downgrade(&self.state),
+                        topic.to_string(),
+                        name.clone(),
+                        entry_id,
+                        delay,
+                    );
+                }
+                _ => sub.dispatch(topic, entries, entry_id),
+            }
+        }
+    }
+}
+
+impl SubscriptionState {
+    fn new(sub_type: SubType) -> Self {
+        Self {
+            sub_type,
+            consumers: Vec::new(),
+            backlog: VecDeque::new(),
+            unacked: HashMap::new(),
+            redelivery_counts: HashMap::new(),
+            delayed: 0,
+            next_round_robin: 0,
+        }
+    }
+
+    /// Picks the consumer of an entry according to the subscription type
+    fn pick_consumer(&mut self, key: Option<&str>) -> Option<&ConsumerSlot> {
+        if self.consumers.is_empty() {
+            return None;
+        }
+
+        let index = match self.sub_type {
+            SubType::Shared => {
+                self.next_round_robin = self.next_round_robin.wrapping_add(1);
+                self.next_round_robin % self.consumers.len()
+            }
+            SubType::KeyShared => {
+                let mut hasher = DefaultHasher::new();
+                key.unwrap_or_default().hash(&mut hasher);
+                (hasher.finish() % self.consumers.len() as u64) as usize
+            }
+            // Exclusive and Failover: the first consumer is the active one
+            _ => 0,
+        };
+
+        self.consumers.get(index)
+    }
+
+    fn dispatch(&mut self, topic: &str, entries: &[Payload], entry_id: u64) {
+        let Some(payload) = entries.get(entry_id as usize) else {
+            return;
+        };
+        let redelivery_count = self.redelivery_counts.get(&entry_id).copied().unwrap_or(0);
+
+        let delivered_to = self
+            .pick_consumer(payload.metadata.partition_key.as_deref())
+            .and_then(|consumer| {
+                let delivery = Delivery {
+                    topic: topic.to_string(),
+                    payload: payload.clone(),
+                    message_id: message_id(entry_id),
+                    redelivery_count,
+                };
+                consumer.tx.send(delivery).ok().map(|()| consumer.id)
+            });
+
+        match delivered_to {
+            Some(consumer_id) => {
+                self.unacked.insert(
+                    entry_id,
+                    Unacked {
+                        consumer_id,
+                        delivered_at: Instant::now(),
+                    },
+                );
+            }
+            None => self.backlog.push_back(entry_id),
+        }
+    }
+
+    fn redeliver(&mut self, topic: &str, entries: &[Payload], entry_id: u64) {
+        if self.unacked.remove(&entry_id).is_some() {
+            *self.redelivery_counts.entry(entry_id).or_default() += 1;
+            self.dispatch(topic, entries, entry_id);
+        }
+    }
+
+    /// Whether `entry_id` is in-flight on `consumer_id`
+    fn delivered_to(&self, consumer_id: u64, entry_id: u64) -> bool {
+        self.unacked
+            .get(&entry_id)
+            .is_some_and(|unacked| unacked.consumer_id == consumer_id)
+    }
+
+    fn ack(&mut self, consumer_id: u64, entry_id: u64) {
+        if self.delivered_to(consumer_id, entry_id) {
+            self.unacked.remove(&entry_id);
+            self.redelivery_counts.remove(&entry_id);
+        }
+    }
+
+    fn nack(&mut self, topic: &str, entries: &[Payload], consumer_id: u64, entry_id: u64) {
+        if self.delivered_to(consumer_id, entry_id) {
+            self.redeliver(topic, entries, entry_id);
+        }
+    }
+
+    /// Entries delivered to `consumer_id` and not acked within `delay`
+    fn expired(&self, consumer_id: u64, delay: Duration) -> Vec<u64> {
+        self.unacked
+            .iter()
+            .filter(|(_, unacked)| unacked.consumer_id == consumer_id && unacked.delivered_at.elapsed() >= delay)
+            .map(|(entry_id, _)| *entry_id)
+            .collect()
+    }
+
+    fn remove_consumer(&mut self, topic: &str, entries: &[Payload], consumer_id: u64) {
+        self.consumers.retain(|consumer| consumer.id != consumer_id);
+
+        let mut orphans: Vec<u64> = self
+            .unacked
+            .iter()
+            .filter(|(_, unacked)| unacked.consumer_id == consumer_id)
+            .map(|(entry_id, _)| *entry_id)
+            .collect();
+        orphans.sort_unstable();
+
+        for entry_id in orphans {
+            self.redeliver(topic, entries, entry_id);
+        }
+    }
+}
+
+fn message_id(entry_id: u64) -> MessageIdData {
+    MessageIdData {
+        ledger_id: 0,
+        entry_id,
+        ..Default::default()
+    }
+}
+
+/// Periodically redelivers the messages a consumer kept unacked for longer than `delay`
+///
+/// stops once the broker is dropped
+fn spawn_resend_task(
+    state: std::sync::Weak<Mutex<State>>,
+    topics: Vec<String>,
+    subscription: String,
+    consumer_id: u64,
+    delay: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval((delay / 4).max(Duration::from_millis(1)));
+
+        loop {
+            interval.tick().await;
+
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+
+            for topic_name in &topics {
+                let Some(Topic {
+                    entries,
+                    subscriptions,
+                }) = state.topics.get_mut(topic_name)
+                else {
+                    continue;
+                };
+                let Some(sub) = subscriptions.get_mut(&subscription) else {
+                    continue;
+                };
+
+                let mut expired = sub.expired(consumer_id, delay);
+                expired.sort_unstable();
+                for entry_id in expired {
+                    sub.redeliver(topic_name, entries, entry_id);
+                }
+            }
+        }
+    })
+}
+
+/// Dispatches an entry once its deliver_at time is reached
+fn spawn_delayed_dispatch(
+    state: std::sync::Weak<Mutex<State>>,
+    topic: String,
+    subscription: String,
+    entry_id: u64,
+    delay: Duration,
+) {
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(Topic {
+            entries,
+            subscriptions,
+        }) = state.topics.get_mut(&topic)
+        {
+            if let Some(sub) = subscriptions.get_mut(&subscription) {
+                sub.delayed -= 1;
+                sub.dispatch(&topic, entries, entry_id);
+            }
+        }
+    });
+}
+
+/// Consumer of a `MemoryBroker` subscription
+pub struct MemorySubscription<T> {
+    broker: MemoryBroker,
+    consumer_id: u64,
+    subscription: String,
+    topics: Vec<String>,
+    rx: mpsc::UnboundedReceiver<Delivery>,
+    resend_task: Option<JoinHandle<()>>,
+    closed: bool,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> MemorySubscription<T> {
+    fn with_subscription(&self, topic: &str, f: impl FnOnce(&mut SubscriptionState, &[Payload])) {
+        let mut state = self.broker.lock();
+        if let Some(Topic {
+            entries,
+            subscriptions,
+        }) = state.topics.get_mut(topic)
+        {
+            if let Some(sub) = subscriptions.get_mut(&self.subscription) {
+                f(sub, entries);
+            }
+        }
+    }
+
+    fn detach(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+
+        if let Some(resend_task) = self.resend_task.take() {
+            resend_task.abort();
+        }
+
+        for topic in self.topics.clone() {
+            self.with_subscription(&topic, |sub, entries| {
+                sub.remove_consumer(&topic, entries, self.consumer_id)
+            });
+        }
+    }
+}
+
+impl<T: DeserializeMessage + Send + 'static> Subscription<T> for MemorySubscription<T> {
+    async fn next(&mut self) -> Result<Option<Message<T>>> {
+        Ok(self.rx.recv().await.map(|delivery| {
+            Message::new(
+                delivery.topic,
+                delivery.payload,
+                delivery.message_id,
+                delivery.redelivery_count,
+            )
+        }))
+    }
+
+    async fn ack(&mut self, topic: &str, message_id: MessageIdData) -> Result<()> {
+        let consumer_id = self.consumer_id;
+        self.with_subscription(topic, |sub, _| sub.ack(consumer_id, message_id.entry_id));
+        Ok(())
+    }
+
+    async fn ack_all(&mut self, topic: &str, message_ids: Vec<MessageIdData>) -> Result<()> {
+        let consumer_id = self.consumer_id;
+        self.with_subscription(topic, |sub, _| {
+            for message_id in message_ids {
+                sub.ack(consumer_id, message_id.entry_id);
+            }
+        });
+        Ok(())
+    }
+
+    async fn cumulative_ack(&mut self, topic: &str, message_id: MessageIdData) -> Result<()> {
+        let consumer_id = self.consumer_id;
+        self.with_subscription(topic, |sub, _| {
+            let acked: Vec<u64> = sub
+                .unacked
+                .iter()
+                .filter(|(entry_id, unacked)| unacked.consumer_id == consumer_id && **entry_id <= message_id.entry_id)
+                .map(|(entry_id, _)| *entry_id)
+                .collect();
+
+            for entry_id in acked {
+                sub.ack(consumer_id, entry_id);
+            }
+        });
+        Ok(())
+    }
+
+    async fn nack(&mut self, topic: &str, message_id: MessageIdData) -> Result<()> {
+        let consumer_id = self.consumer_id;
+        self.with_subscription(topic, |sub, entries| {
+            sub.nack(topic, entries, consumer_id, message_id.entry_id)
+        });
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.detach();
+        Ok(())
+    }
+}
+
+impl<T> Drop for MemorySubscription<T> {
+    fn drop(&mut self) {
+        self.detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::TestData;
+
+    const TOPIC: &str = "topic";
+    const SUBSCRIPTION: &str = "subscription";
+
+    type TestSubscription = MemorySubscription<TestData>;
+
+    fn options(subscription_type: SubType) -> SubscribeOptions {
+        SubscribeOptions {
+            topics: vec![TOPIC.to_string()],
+            consumer_name: "consumer".to_string(),
+            subscription: SUBSCRIPTION.to_string(),
+            subscription_type,
+            unacked_resend_delay: None,
+        }
+    }
+
+    async fn subscribe(broker: &MemoryBroker, options: &SubscribeOptions) -> TestSubscription {
+        broker.subscribe(options).await.unwrap()
+    }
+
+    fn keyed(key: &str) -> producer::Message {
+        producer::Message {
+            partition_key: Some(key.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Next message delivered to `subscription` within 100ms
+    async fn next(subscription: &mut TestSubscription) -> Option<Message<TestData>> {
+        let next = timeout(Duration::from_millis(100), subscription.next()).await.ok()?;
+        next.unwrap()
+    }
+
+    #[tokio::test]
+    async fn subscriptions_start_at_the_latest_message() {
+        let broker = MemoryBroker::new();
+        broker.produce(TOPIC, keyed("a")).await.unwrap();
+        let mut subscription = subscribe(&broker, &options(SubType::Shared)).await;
+        broker.produce(TOPIC, keyed("b")).await.unwrap();
+
+        let msg = next(&mut subscription).await.unwrap();
+        assert_eq!(msg.key(), Some("b"));
+        assert_eq!(msg.message_id.entry_id, 1);
+        assert!(next(&mut subscription).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn conflicting_consumers_are_rejected() {
+        let broker = MemoryBroker::new();
+        let _exclusive = subscribe(&broker, &options(SubType::Exclusive)).await;
+
+        assert!(broker.subscribe::<TestData>(&options(SubType::Exclusive)).await.is_err());
+        assert!(broker.subscribe::<TestData>(&options(SubType::Shared)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn consumers_only_ack_and_nack_their_own_messages() {
+        let broker = MemoryBroker::new();
+        let mut first = subscribe(&broker, &options(SubType::Shared)).await;
+        let mut second = subscribe(&broker, &options(SubType::Shared)).await;
+        broker.produce(TOPIC, keyed("a")).await.unwrap();
+        broker.produce(TOPIC, keyed("b")).await.unwrap();
+        let mine = next(&mut first).await.unwrap();
+        let theirs = next(&mut second).await.unwrap();
+        assert_ne!(mine.message_id.entry_id, theirs.message_id.entry_id);
+
+        first.ack(TOPIC, theirs.message_id.clone()).await.unwrap();
+        first.ack_all(TOPIC, vec![theirs.message_id.clone()]).await.unwrap();
+        first.nack(TOPIC, theirs.message_id.clone()).await.unwrap();
+        assert_eq!(broker.pending(TOPIC, SUBSCRIPTION), 2);
+        assert!(next(&mut first).await.is_none());
+        assert!(next(&mut second).await.is_none());
+
+        first.ack(TOPIC, mine.message_id).await.unwrap();
+        second.ack(TOPIC, theirs.message_id).await.unwrap();
+        assert_eq!(broker.pending(TOPIC, SUBSCRIPTION), 0);
+    }
+
+    #[tokio::test]
+    async fn cumulative_ack_covers_the_consumer_messages_up_to_the_entry() {
+        let broker = MemoryBroker::new();
+        let mut subscription = subscribe(&broker, &options(SubType::Failover)).await;
+        for key in ["a", "b", "c"] {
+            broker.produce(TOPIC, keyed(key)).await.unwrap();
+        }
+        let mut ids = Vec::new();
+        while let Some(msg) = next(&mut subscription).await {
+            ids.push(msg.message_id);
+        }
+
+        subscription.cumulative_ack(TOPIC, ids[1].clone()).await.unwrap();
+
+        assert_eq!(broker.pending(TOPIC, SUBSCRIPTION), 1);
+    }
+
+    #[tokio::test]
+    async fn nack_redelivers_with_a_higher_redelivery_count() {
+        let broker = MemoryBroker::new();
+        let mut subscription = subscribe(&broker, &options(SubType::Shared)).await;
+        broker.produce(TOPIC, keyed("a")).await.unwrap();
+
+        let msg = next(&mut subscription).await.unwrap();
+        assert_eq!(msg.redelivery_count, 0);
+        subscription.nack(TOPIC, msg.message_id).await.unwrap();
+
+        let msg = next(&mut subscription).await.unwrap();
+        assert_eq!(msg.redelivery_count, 1);
+        subscription.ack(TOPIC, msg.message_id).await.unwrap();
+        assert_eq!(broker.pending(TOPIC, SUBSCRIPTION), 0);
+    }
+
+    #[tokio::test]
+    async fn key_shared_keeps_a_key_on_one_consumer() {
+        let broker = MemoryBroker::new();
+        let mut consumers = Vec::new();
+        for _ in 0..3 {
+            consumers.push(subscribe(&broker, &options(SubType::KeyShared)).await);
+        }
+        for _ in 0..3 {
+            for key in ["a", "b", "c", "d"] {
+                broker.produce(TOPIC, keyed(key)).await.unwrap();
+            }
+        }
+
+        let mut owners = HashMap::new();
+        for (index, consumer) in consumers.iter_mut().enumerate() {
+            while let Some(msg) = next(consumer).await {
+                let owner = *owners.entry(msg.key().unwrap().to_string()).or_insert(index);
+                assert_eq!(owner, index);
+            }
+        }
+        assert_eq!(owners.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn closing_redelivers_to_the_remaining_consumers_and_ends_the_stream() {
+        let broker = MemoryBroker::new();
+        let mut closing = subscribe(&broker, &options(SubType::Failover)).await;
+        let mut standby = subscribe(&broker, &options(SubType::Failover)).await;
+        broker.produce(TOPIC, keyed("a")).await.unwrap();
+        assert!(next(&mut closing).await.is_some());
+        assert!(next(&mut standby).await.is_none());
+
+        closing.close().await.unwrap();
+
+        assert!(closing.next().await.unwrap().is_none());
+        let msg = next(&mut standby).await.unwrap();
+        assert_eq!(msg.redelivery_count, 1);
+    }
+
+    #[tokio::test]
+    async fn unacked_messages_are_resent_after_the_delay() {
+        let broker = MemoryBroker::new();
+        let options = SubscribeOptions {
+            unacked_resend_delay: Some(Duration::from_millis(40)),
+            ..options(SubType::Shared)
+        };
+        let mut subscription = subscribe(&broker, &options).await;
+        broker.produce(TOPIC, keyed("a")).await.unwrap();
+
+        let started = Instant::now();
+        assert!(next(&mut subscription).await.is_some());
+        let msg = next(&mut subscription).await.unwrap();
+
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        assert_eq!(msg.redelivery_count, 1);
+    }
+
+    #[tokio::test]
+    async fn produce_at_only_delays_shared_subscriptions() {
+        let broker = MemoryBroker::new();
+        let mut shared = subscribe(&broker, &options(SubType::Shared)).await;
+        let mut exclusive = subscribe(
+            &broker,
+            &SubscribeOptions {
+                subscription: "exclusive".to_string(),
+                ..options(SubType::Exclusive)
+            },
+        )
+        .await;
+        let deliver_at = SystemTime::now() + Duration::from_millis(150);
+        broker.produce_at(TOPIC, keyed("a"), deliver_at).await.unwrap();
+
+        assert!(next(&mut exclusive).await.is_some());
+        assert!(next(&mut shared).await.is_none());
+        assert_eq!(broker.pending(TOPIC, SUBSCRIPTION), 1);
+        assert!(next(&mut shared).await.is_some());
+        assert!(SystemTime::now() >= deliver_at);
+    }
+}
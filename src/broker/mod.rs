@@ -0,0 +1,140 @@
+mod memory;
+pub use memory::*;
+
+mod pulsar_broker;
+pub use pulsar_broker::*;
+
+use std::{
+    future::Future,
+    time::{Duration, SystemTime},
+};
+
+use pulsar::{message::proto::MessageIdData, producer, DeserializeMessage, SubType};
+use tokio::time::sleep;
+use tracing::warn;
+
+use crate::{
+    error::{Error, Result},
+    message::Message,
+    shutdown::Shutdown,
+};
+
+/// Subscription settings shared by every broker backend
+#[derive(Debug, Clone)]
+pub struct SubscribeOptions {
+    pub topics: Vec<String>,
+    pub consumer_name: String,
+    pub subscription: String,
+    pub subscription_type: SubType,
+    /// delay before a message that was neither acked nor nacked is delivered again
+    pub unacked_resend_delay: Option<Duration>,
+}
+
+/// Message broker the pipeline reads from and publishes to
+///
+/// implemented by `PulsarBroker` and by `MemoryBroker` for hermetic tests
+pub trait Broker: Clone + Send + Sync + 'static {
+    type Subscription<T: DeserializeMessage + Send + 'static>: Subscription<T>;
+
+    /// Creates a consumer on the subscription, the subscription itself is created if missing
+    fn subscribe<T: DeserializeMessage + Send + 'static>(
+        &self,
+        options: &SubscribeOptions,
+    ) -> impl Future<Output = Result<Self::Subscription<T>>> + Send;
+
+    /// Publishes a message and waits for the broker receipt
+    fn produce(&self, topic: &str, message: producer::Message) -> impl Future<Output = Result<()>> + Send;
+
+    /// Publishes a message delivered at `deliver_at`
+    ///
+    /// like pulsar, only Shared subscriptions delay it, the other types get it right away
+    fn produce_at(
+        &self,
+        topic: &str,
+        message: producer::Message,
+        deliver_at: SystemTime,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// One consumer of a subscription
+pub trait Subscription<T>: Send + 'static {
+    /// Next message delivered to this consumer, waits for one
+    ///
+    /// `Ok(None)` once the consumer stream ended, e.g. it was closed, nothing comes after it
+    fn next(&mut self) -> impl Future<Output = Result<Option<Message<T>>>> + Send;
+
+    fn ack(&mut self, topic: &str, message_id: MessageIdData) -> impl Future<Output = Result<()>> + Send;
+
+    /// Acks several messages of `topic` at once
+    fn ack_all(&mut self, topic: &str, message_ids: Vec<MessageIdData>) -> impl Future<Output = Result<()>> + Send;
+
+    /// Acks `message_id` and every message delivered before it on `topic`
+    ///
+    /// only valid on Exclusive and Failover subscriptions
+    fn cumulative_ack(&mut self, topic: &str, message_id: MessageIdData) -> impl Future<Output = Result<()>> + Send;
+
+    /// Negative ack, the message is delivered again
+    fn nack(&mut self, topic: &str, message_id: MessageIdData) -> impl Future<Output = Result<()>> + Send;
+
+    /// Removes the consumer, its unacked messages are delivered to the remaining consumers
+    fn close(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// First pause after a failed read
+const RECEIVE_BACKOFF_BASE: Duration = Duration::from_millis(100);
+/// Longest pause between failed reads
+const RECEIVE_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// Pause between reads of a subscription failing in a row, so a broken consumer does not spin
+///
+/// doubles from `RECEIVE_BACKOFF_BASE` up to `RECEIVE_BACKOFF_MAX`, `reset` it once a read
+/// succeeds
+#[derive(Debug, Default)]
+pub struct ReceiveBackoff {
+    failures: u32,
+}
+
+impl ReceiveBackoff {
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Pause after one more failure
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = RECEIVE_BACKOFF_BASE
+            .saturating_mul(1 << self.failures.min(16))
+            .min(RECEIVE_BACKOFF_MAX);
+        self.failures = self.failures.saturating_add(1);
+        delay
+    }
+
+    /// Logs the failed read then pauses, `false` if shutdown was requested meanwhile
+    pub async fn wait(&mut self, error: &Error, shutdown: &mut Shutdown) -> bool {
+        let delay = self.next_delay();
+        warn!(%error, ?delay, "receive failed, retrying later");
+
+        tokio::select! {
+            _ = sleep(delay) => true,
+            _ = shutdown.recv() => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receive_backoff_doubles_up_to_the_max_until_reset() {
+        let mut backoff = ReceiveBackoff::default();
+
+        let delays: Vec<u64> = (0..8).map(|_| backoff.next_delay().as_millis() as u64).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1600, 3200, 5000, 5000]);
+        for _ in 0..100 {
+            assert_eq!(backoff.next_delay(), RECEIVE_BACKOFF_MAX);
+        }
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), RECEIVE_BACKOFF_BASE);
+    }
+}
@@ -0,0 +1,399 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures::TryStreamExt;
+use pulsar::{
+    message::proto::MessageIdData, producer, Consumer, DeserializeMessage, Producer, Pulsar, TokioExecutor,
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    error::{Error, Result},
+    message::Message,
+};
+
+use super::{Broker, SubscribeOptions, Subscription};
+
+/// `Broker` backed by a real pulsar cluster
+#[derive(Clone)]
+pub struct PulsarBroker {
+    client: Pulsar<TokioExecutor>,
+    // one producer per topic, created on the first message
+    producers: Arc<Mutex<HashMap<String, Producer<TokioExecutor>>>>,
+}
+
+impl PulsarBroker {
+    pub fn new(client: Pulsar<TokioExecutor>) -> Self {
+        Self {
+            client,
+            producers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn connect(addr: &str) -> Result<Self> {
+        let client = Pulsar::builder(addr, TokioExecutor)
+            .build()
+            .await
+            .map_err(Error::Build)?;
+
+        Ok(Self::new(client))
+    }
+
+    pub fn client(&self) -> &Pulsar<TokioExecutor> {
+        &self.client
+    }
+
+    /// Producer of `topic`, created on the first call
+    async fn producer<'a>(
+        &self,
+        producers: &'a mut HashMap<String, Producer<TokioExecutor>>,
+        topic: &str,
+    ) -> Result<&'a mut Producer<TokioExecutor>> {
+        if !producers.contains_key(topic) {
+            let producer = self
+                .client
+                .producer()
+                .with_topic(topic)
+                .build()
+                .await
+                .map_err(Error::Build)?;
+            producers.insert(topic.to_string(), producer);
+        }
+
+        producers
+            .get_mut(topic)
+            .ok_or(Error::Produce(pulsar::Error::Custom(format!("no producer for {}", topic))))
+    }
+}
+
+impl Broker for PulsarBroker {
+    type Subscription<T: DeserializeMessage + Send + 'static> = PulsarSubscription<T>;
+
+    async fn subscribe<T: DeserializeMessage + Send + 'static>(
+        &self,
+        options: &SubscribeOptions,
+    ) -> Result<PulsarSubscription<T>> {
+        let pulsar_consumer: Consumer<T, _> = self
+            .client
+            .consumer()
+            .with_topics(&options.topics)
+            .with_consumer_name(&options.consumer_name)
+            .with_subscription_type(options.subscription_type)
+            .with_subscription(&options.subscription)
+            .with_unacked_message_resend_delay(options.unacked_resend_delay)
+            .build()
+            .await
+            .map_err(Error::Build)?;
+
+        Ok(PulsarSubscription {
+            pulsar_consumer,
+            redelivery_counts: NackCounts::new(NACK_COUNT_TTL, MAX_NACK_COUNTS),
+        })
+    }
+
+    async fn produce(&self, topic: &str, message: producer::Message) -> Result<()> {
+        let mut producers = self.producers.lock().await;
+        let producer = self.producer(&mut producers, topic).await?;
+
+        let receipt = producer.send_non_blocking(message).await.map_err(Error::Produce)?;
+        // release the producers before waiting for the broker receipt
+        drop(producers);
+
+        receipt.await.map_err(Error::Produce)?;
+        Ok(())
+    }
+
+    async fn produce_at(&self, topic: &str, message: producer::Message, deliver_at: SystemTime) -> Result<()> {
+        let mut producers = self.producers.lock().await;
+        let producer = self.producer(&mut producers, topic).await?;
+
+        // deliver_at is only exposed by the message builder, which sets the metadata itself
+        let mut builder = producer
+            .create_message()
+            .with_content(message.payload)
+            .deliver_at(deliver_at)
+            .map_err(|e| Error::Produce(pulsar::Error::Custom(e.to_string())))?;
+        for (key, value) in message.properties {
+            builder = builder.with_property(key, value);
+        }
+        if let Some(partition_key) = message.partition_key {
+            builder = builder.with_partition_key(partition_key);
+        }
+        if let Some(ordering_key) = message.ordering_key {
+            builder = builder.with_ordering_key(ordering_key);
+        }
+        if let Some(event_time) = message.event_time {
+            builder = builder.event_time(event_time);
+        }
+
+        let receipt = builder.send_non_blocking().await.map_err(Error::Produce)?;
+        drop(producers);
+
+        receipt.await.map_err(Error::Produce)?;
+        Ok(())
+    }
+}
+
+/// Consumer of a pulsar subscription
+///
+/// the pulsar client drops the broker redelivery count, so nacks are counted here instead.
+/// The count is local to this consumer: it is lost on restart and when the message is
+/// redelivered to another consumer (KeyShared rebalancing, unacked resend after a close), so
+/// `retry_max_attempts` is only enforced while a message keeps coming back to the same
+/// consumer. Use retry letters, which carry the attempt in a property, for a strict limit
+///
+/// counts are dropped on ack, and after `NACK_COUNT_TTL` without a nack for the messages that
+/// never came back, at most `MAX_NACK_COUNTS` are kept
+pub struct PulsarSubscription<T: DeserializeMessage> {
+    pulsar_consumer: Consumer<T, TokioExecutor>,
+    redelivery_counts: NackCounts,
+}
+
+/// A nacked message comes back within the retry backoff, kept below the unacked resend delay,
+/// a count untouched for longer belongs to a message delivered elsewhere
+const NACK_COUNT_TTL: Duration = Duration::from_secs(10 * 60);
+/// Upper bound of the counts kept, the least recently nacked are dropped first
+const MAX_NACK_COUNTS: usize = 100_000;
+
+/// Hashable identity of a `MessageIdData`, ordered by topic then position
+type MessageKey = (String, u64, u64, Option<i32>, Option<i32>);
+
+fn message_key(topic: &str, message_id: &MessageIdData) -> MessageKey {
+    (
+        topic.to_string(),
+        message_id.ledger_id,
+        message_id.entry_id,
+        message_id.partition,
+        message_id.batch_index,
+    )
+}
+
+/// Nacks per message of one consumer, bounded in time and size
+///
+/// every operation is logarithmic: the counts are ordered by message and indexed by last nack
+/// so the stale ones are popped from the front of the index
+struct NackCounts {
+    // count and last nack of every message
+    counts: BTreeMap<MessageKey, (u32, Instant)>,
+    // the same messages ordered by last nack
+    by_age: BTreeSet<(Instant, MessageKey)>,
+    ttl: Duration,
+    max_len: usize,
+}
+
+impl NackCounts {
+    fn new(ttl: Duration, max_len: usize) -> Self {
+        Self {
+            counts: BTreeMap::new(),
+            by_age: BTreeSet::new(),
+            ttl,
+            max_len,
+        }
+    }
+
+    fn get(&self, key: &MessageKey) -> u32 {
+        self.counts.get(key).map_or(0, |(count, _)| *count)
+    }
+
+    fn nacked(&mut self, key: MessageKey, now: Instant) {
+        let count = match self.counts.remove(&key) {
+            Some((count, last_nacked)) => {
+                self.by_age.remove(&(last_nacked, key.clone()));
+                count
+            }
+            None => 0,
+        };
+        self.by_age.insert((now, key.clone()));
+        self.counts.insert(key, (count + 1, now));
+        self.expire(now);
+    }
+
+    fn acked(&mut self, key: &MessageKey) {
+        if let Some((_, last_nacked)) = self.counts.remove(key) {
+            self.by_age.remove(&(last_nacked, key.clone()));
+        }
+    }
+
+    /// Drops the counts of `topic` up to `(ledger_id, entry_id)`, included
+    fn acked_up_to(&mut self, topic: &str, ledger_id: u64, entry_id: u64) {
+        let first = (topic.to_string(), 0, 0, None, None);
+        let last = (topic.to_string(), ledger_id, entry_id, Some(i32::MAX), Some(i32::MAX));
+        let acked: Vec<MessageKey> = self.counts.range(first..=last).map(|(key, _)| key.clone()).collect();
+        for key in acked {
+            self.acked(&key);
+        }
+    }
+
+    /// Drops the counts not nacked for `ttl`, then the oldest ones above `max_len`
+    fn expire(&mut self, now: Instant) {
+        while let Some((last_nacked, _)) = self.by_age.first() {
+            let expired = now.saturating_duration_since(*last_nacked) >= self.ttl;
+            if !expired && self.counts.len() <= self.max_len {
+                break;
+            }
+            if let Some((_, key)) = self.by_age.pop_first() {
+                self.counts.remove(&key);
+            }
+        }
+    }
+}
+
+impl<T: DeserializeMessage + Send + 'static> Subscription<T> for PulsarSubscription<T> {
+    async fn next(&mut self) -> Result<Option<Message<T>>> {
+        let msg = match self.pulsar_consumer.try_next().await {
+            Ok(Some(msg)) => msg,
+            // the consumer engine is gone, nothing will be delivered anymore
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(Error::Consume(e)),
+        };
+
+        let redelivery_count = self
+            .redelivery_counts
+            .get(&message_key(&msg.topic, &msg.message_id.id));
+
+        Ok(Some(Message::new(msg.topic, msg.payload, msg.message_id.id, redelivery_count)))
+    }
+
+    async fn ack(&mut self, topic: &str, message_id: MessageIdData) -> Result<()> {
+        self.redelivery_counts.acked(&message_key(topic, &message_id));
+        self.pulsar_consumer
+            .ack_with_id(topic, message_id)
+            .await
+            .map_err(Error::Ack)
+    }
+
+    /// pulsar-rs has no public list ack (the `CommandAck` with several ids is internal), so the
+    /// ids are acked one by one. Each ack only queues a command to the consumer engine, the
+    /// broker does not answer acks
+    async fn ack_all(&mut self, topic: &str, message_ids: Vec<MessageIdData>) -> Result<()> {
+        for message_id in message_ids {
+            self.ack(topic, message_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn cumulative_ack(&mut self, topic: &str, message_id: MessageIdData) -> Result<()> {
+        self.redelivery_counts
+            .acked_up_to(topic, message_id.ledger_id, message_id.entry_id);
+
+        self.pulsar_consumer
+            .cumulative_ack_with_id(topic, message_id)
+            .await
+            .map_err(Error::Ack)
+    }
+
+    async fn nack(&mut self, topic: &str, message_id: MessageIdData) -> Result<()> {
+        self.redelivery_counts
+            .nacked(message_key(topic, &message_id), Instant::now());
+
+        self.pulsar_consumer
+            .nack_with_id(topic, message_id)
+            .await
+            .map_err(Error::Ack)
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.pulsar_consumer.close().await.map_err(Error::Close)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(entry_id: u64) -> MessageKey {
+        ("topic".to_string(), 1, entry_id, None, None)
+    }
+
+    #[test]
+    fn counts_nacks_until_acked() {
+        let mut counts = NackCounts::new(NACK_COUNT_TTL, 10);
+        let now = Instant::now();
+
+        counts.nacked(key(1), now);
+        counts.nacked(key(1), now);
+        counts.nacked(key(2), now);
+        assert_eq!((counts.get(&key(1)), counts.get(&key(2)), counts.get(&key(3))), (2, 1, 0));
+
+        counts.acked(&key(1));
+        assert_eq!(counts.get(&key(1)), 0);
+
+        counts.nacked(key(3), now);
+        counts.acked_up_to("topic", 1, 2);
+        assert_eq!((counts.get(&key(2)), counts.get(&key(3))), (0, 1));
+    }
+
+    #[test]
+    fn expires_counts_not_nacked_for_the_ttl() {
+        let mut counts = NackCounts::new(Duration::from_secs(60), 10);
+        let start = Instant::now();
+
+        counts.nacked(key(1), start);
+        counts.nacked(key(2), start + Duration::from_secs(30));
+        // nacked again, its ttl starts over
+        counts.nacked(key(1), start + Duration::from_secs(40));
+        counts.nacked(key(3), start + Duration::from_secs(91));
+
+        assert_eq!(counts.get(&key(2)), 0);
+        assert_eq!((counts.get(&key(1)), counts.get(&key(3))), (2, 1));
+        assert_eq!(counts.by_age.len(), 2);
+    }
+
+    #[test]
+    fn drops_the_oldest_counts_above_max_len() {
+        let mut counts = NackCounts::new(NACK_COUNT_TTL, 3);
+        let start = Instant::now();
+
+        for entry_id in 0..5 {
+            counts.nacked(key(entry_id), start + Duration::from_millis(entry_id));
+        }
+
+        assert_eq!(counts.counts.len(), 3);
+        assert_eq!((counts.get(&key(0)), counts.get(&key(1))), (0, 0));
+        assert_eq!((counts.get(&key(2)), counts.get(&key(3)), counts.get(&key(4))), (1, 1, 1));
+    }
+
+    #[test]
+    fn stays_bounded_when_nacked_past_max_len_repeatedly() {
+        let max_len = 1000;
+        let mut counts = NackCounts::new(NACK_COUNT_TTL, max_len);
+        let start = Instant::now();
+
+        for round in 0..5u64 {
+            for entry_id in 0..(max_len as u64 * 2) {
+                let now = start + Duration::from_micros(round * 10_000 + entry_id);
+                counts.nacked(key(entry_id), now);
+                assert!(counts.counts.len() <= max_len);
+                assert_eq!(counts.by_age.len(), counts.counts.len());
+            }
+        }
+
+        // the most recently nacked half is left, its counts were evicted by the other half
+        // at the start of every round
+        assert_eq!(counts.counts.len(), max_len);
+        assert_eq!(counts.get(&key(max_len as u64 - 1)), 0);
+        assert_eq!(counts.get(&key(max_len as u64)), 1);
+        assert_eq!(counts.get(&key(2 * max_len as u64 - 1)), 1);
+    }
+
+    #[test]
+    fn cumulative_ack_only_drops_its_topic_up_to_the_entry() {
+        let mut counts = NackCounts::new(NACK_COUNT_TTL, 10);
+        let now = Instant::now();
+        let batched = |entry_id, batch_index| ("topic".to_string(), 1, entry_id, Some(0), Some(batch_index));
+
+        counts.nacked(batched(2, 0), now);
+        counts.nacked(batched(2, 5), now);
+        counts.nacked(batched(3, 0), now);
+        counts.nacked(("other".to_string(), 1, 1, None, None), now);
+        counts.acked_up_to("topic", 1, 2);
+
+        assert_eq!((counts.get(&batched(2, 0)), counts.get(&batched(2, 5))), (0, 0));
+        assert_eq!(counts.get(&batched(3, 0)), 1);
+        assert_eq!(counts.get(&("other".to_string(), 1, 1, None, None)), 1);
+        assert_eq!(counts.by_age.len(), 2);
+    }
+}
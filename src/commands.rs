@@ -0,0 +1,383 @@
+use std::{
+    collections::BTreeSet,
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use pulsar::producer;
+use tokio::time::{interval, MissedTickBehavior};
+use tokio_metrics::TaskMonitor;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::{
+    actors,
+    batch::BatchConsumer,
+    bench::{BenchReport, ConsumeResult, ProduceResult, Scenario},
+    broker::{Broker, PulsarBroker, Subscription},
+    compute::ComputePool,
+    config::{ConsumeMode, Config},
+    dead_letter::DeadLetter,
+    error::{Error, Result},
+    handler::{ConcurrentBatchHandler, PooledHandler},
+    latency::{self, Latencies},
+    metrics::{self, Metrics},
+    retry_letter::retry_topic,
+    shutdown::{self, Shutdown},
+    system::SystemInfo,
+    SimulatedWorkHandler, TestData,
+};
+
+/// Produces `messages_per_topic` `TestData` messages on every producer topic, their
+/// partition key follows the configured key distribution
+///
+/// returns the number of messages the broker confirmed
+pub async fn produce(config: &Config) -> Result<usize> {
+    let broker = PulsarBroker::connect(&config.pulsar.addr).await?;
+    let pulsar = broker.client();
+
+    let mut v = Vec::new();
+
+    for topic in &config.producer.topics {
+        let mut producer = pulsar
+            .producer()
+            .with_topic(topic)
+            .with_name(config.producer.name.clone())
+            .with_options(producer::ProducerOptions {
+                batch_size: Some(config.producer.batch_size),
+                ..Default::default()
+            })
+            .build()
+            .await
+            .map_err(Error::Build)?;
+
+        for i in 0..config.producer.messages_per_topic {
+            let keys = &config.producer.partition_keys;
+            let partition_key = config
+                .producer
+                .key_distribution
+                .pick(i, keys.len())
+                .map(|index| keys[index].clone())
+                .unwrap_or_default();
+
+            // the consumers continue this trace, its context is written in the message properties
+            let span = info_span!("publish", otel.kind = "producer", topic = %topic, partition_key = %partition_key);
+            let receipt_rx = producer
+                .send_non_blocking(TestData {
+                    data: "data".to_string(),
+                    partition_key,
+                })
+                .instrument(span)
+                .await
+                .map_err(Error::Produce)?;
+            v.push(receipt_rx);
+        }
+    }
+
+    let mut confirmed = 0;
+    for receipt in join_all(v).await {
+        match receipt {
+            Ok(_) => confirmed += 1,
+            Err(e) => error!("{}", Error::Produce(e)),
+        }
+    }
+
+    info!(confirmed, "messages produced");
+    Ok(confirmed)
+}
+
+/// Consumes with the configured mode until shutdown is requested
+///
+/// serves the prometheus metrics and samples the runtime meanwhile if they are enabled,
+/// the latency percentiles are logged periodically and once the consumer stopped
+pub async fn consume(config: &Config, shutdown: Shutdown) -> Result<()> {
+    let mut metrics_tasks = if config.metrics.enabled {
+        let addr = config
+            .metrics
+            .addr
+            .parse()
+            .map_err(|e| Error::Config(format!("metrics addr {:?}: {}", config.metrics.addr, e)))?;
+        vec![
+            metrics::serve(addr, shutdown.clone()).await?,
+            metrics::report(config.metrics.report_interval(), shutdown.clone()),
+        ]
+    } else {
+        Vec::new()
+    };
+    if let Some(every) = config.consumer.latency_report_interval() {
+        metrics_tasks.push(latency::report(every, shutdown.clone()));
+    }
+
+    let result = match config.consumer.mode {
+        ConsumeMode::Batch => consume_batch(config, shutdown).await,
+        ConsumeMode::Actors => consume_actors(config, shutdown).await,
+    };
+
+    for metrics_task in metrics_tasks {
+        if let Err(e) = metrics_task.await {
+            error!(error = ?e, "metrics task failed");
+        }
+    }
+    Latencies::global().log_summary();
+    result
+}
+
+/// Produces the messages then consumes them until every one succeeded or was given up on,
+/// or `shutdown`
+///
+/// the subscriptions are created before producing since a new subscription starts at the
+/// latest message. The topics are expected to have no backlog, older messages would be
+/// counted as well. Returns the throughput, latency percentiles and system info of the run
+pub async fn bench(config: &Config, mut shutdown: Shutdown) -> Result<BenchReport> {
+    let system = SystemInfo::collect().await;
+    info!("{}", system);
+
+    let broker = PulsarBroker::connect(&config.pulsar.addr).await?;
+    let pipeline = config.consumer.pipeline();
+    let mut subscription = broker
+        .subscribe::<TestData>(&pipeline.subscribe_options(config.consumer.topics.names().to_vec()))
+        .await?;
+    subscription.close().await?;
+
+    let started = Instant::now();
+    let produced = produce(config).await?;
+    let produce = ProduceResult::new(produced, started.elapsed());
+
+    let (trigger, consume_shutdown) = shutdown::channel();
+    let topics = config.consumer.topics.names().to_vec();
+    // dead lettered and discarded messages are acked too, a message is done once it
+    // succeeded or was given up on
+    let watcher = tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_millis(100));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let complete = loop {
+            tokio::select! {
+                _ = shutdown.recv() => break false,
+                _ = ticker.tick() => {
+                    let (succeeded, dead_lettered, discarded) = outcomes(&topics);
+                    if succeeded + dead_lettered + discarded >= produced as u64 {
+                        break true;
+                    }
+                }
+            }
+        };
+        trigger.trigger();
+        complete
+    });
+
+    let started = Instant::now();
+    let consumed = consume(config, consume_shutdown).await;
+    let elapsed = started.elapsed();
+    // the consumer may have failed before every message was acked
+    watcher.abort();
+    let complete = matches!(watcher.await, Ok(true));
+    consumed?;
+    if !complete {
+        warn!(produced, "bench stopped before every produced message was handled");
+    }
+
+    let metrics = Metrics::global();
+    let topics = config.consumer.topics.names();
+    let (succeeded, dead_lettered, discarded) = outcomes(topics);
+    let consume = ConsumeResult::new(
+        topic_total(&metrics.acked, topics),
+        topic_total(&metrics.nacked, topics),
+        topic_total(&metrics.failed, topics),
+        elapsed,
+        complete,
+    )
+    .with_outcomes(succeeded, dead_lettered, discarded);
+    if dead_lettered + discarded > 0 {
+        warn!(dead_lettered, discarded, "bench gave up on some messages");
+    }
+    info!(
+        produced,
+        acked = consume.acked,
+        succeeded,
+        produce_per_sec = produce.messages_per_sec,
+        consume_per_sec = consume.messages_per_sec,
+        "bench finished"
+    );
+
+    Ok(BenchReport::new(
+        Scenario::new(config),
+        produce,
+        consume,
+        Latencies::global().summary(),
+        system,
+    ))
+}
+
+/// Messages of `topics` that succeeded, were dead lettered or discarded
+///
+/// retried messages succeed on the retry topics, the other two are counted by origin topic
+fn outcomes(topics: &[String]) -> (u64, u64, u64) {
+    let metrics = Metrics::global();
+    let retry_topics: Vec<String> = topics.iter().map(|topic| retry_topic(topic)).collect();
+
+    (
+        topic_total(&metrics.processed, topics) + topic_total(&metrics.processed, &retry_topics),
+        topic_total(&metrics.dead_lettered, topics),
+        topic_total(&metrics.discarded, topics),
+    )
+}
+
+/// Sum of a per topic counter over `topics`
+fn topic_total(counter: &prometheus::IntCounterVec, topics: &[String]) -> u64 {
+    topics
+        .iter()
+        .map(|topic| counter.with_label_values(&[topic]).get())
+        .sum()
+}
+
+/// Pulsar consumer with the following properties:
+///
+/// **Bounded processing:** The Tokio runtime must control the level of parallelism to prevent unbounded work.
+///
+/// **Batch processing:** The consumer should process N messages at a time with a timeout configuration, allowing for batch acknowledgment (ack) or negative acknowledgment (nack).
+///
+pub async fn consume_batch(config: &Config, shutdown: Shutdown) -> Result<()> {
+    let broker = PulsarBroker::connect(&config.pulsar.addr).await?;
+
+    let pipeline = config.consumer.pipeline();
+    let subscription = broker
+        .subscribe::<TestData>(&pipeline.subscribe_options(config.consumer.topics.names().to_vec()))
+        .await?;
+    let dead_letter = DeadLetter::new(broker.clone(), pipeline.dead_letter_topic, pipeline.subscription);
+
+    let handler = PooledHandler::new(SimulatedWorkHandler, ComputePool::new(config.consumer.compute_concurrency));
+    let handler = ConcurrentBatchHandler::new(handler, config.consumer.max_concurrency)
+        .with_ordering(config.consumer.ordering);
+    let mut batch_consumer = BatchConsumer::new(
+        subscription,
+        handler,
+        config.consumer.batch_size,
+        config.consumer.batch_timeout(),
+        pipeline.retry,
+        dead_letter,
+    );
+
+    batch_consumer.run(shutdown).await;
+    batch_consumer.close().await
+}
+
+/// Receiver per topic feeding one shared Executor -> Acker pipeline
+///
+/// the Executor splits `max_concurrency` between the topics by `topic_weights`. With retry letters
+/// enabled, a second Receiver per topic feeds `<topic>-RETRY` to the channel of its topic
+///
+/// on shutdown: 1. stop the receivers, 2. drain in-flight executor tasks, 3. flush pending acks,
+/// 4. apply them and close the consumers
+pub async fn consume_actors(config: &Config, mut shutdown: Shutdown) -> Result<()> {
+    let broker = PulsarBroker::connect(&config.pulsar.addr).await?;
+
+    // shared by the executor and the acker, the receivers of each topic get its own overrides
+    let pipeline = config.consumer.pipeline();
+
+    // init acker task
+    let acker_handle = actors::AckerHandle::<TestData>::new(&pipeline, broker.clone()).await;
+
+    // one executor shares its permits between the topics
+    let executor_handle = actors::ExecutorHandle::new(
+        PooledHandler::new(SimulatedWorkHandler, ComputePool::new(config.consumer.compute_concurrency)),
+        config.consumer.topics.names(),
+        acker_handle.acker_tx.clone(),
+        &pipeline,
+        shutdown.clone(),
+    )
+    .await;
+
+    // every topic is subscribed before a receiver starts, so a failed subscription can still
+    // stop the executor and the acker, none of them is left detached
+    let mut receivers = Vec::new();
+    let subscribed = async {
+        for (topic, executor_tx) in executor_handle.senders() {
+            let topic_pipeline = config.consumer.topic_pipeline(topic);
+            receivers.push(actors::Receiver::new(&broker, topic.clone(), &topic_pipeline, executor_tx.clone()).await?);
+            // retry letters of the topic are fed to the same executor channel
+            receivers.extend(actors::Receiver::retry(&broker, topic, &topic_pipeline, executor_tx.clone()).await?);
+        }
+        Ok::<_, Error>(())
+    }
+    .await;
+    if let Err(e) = subscribed {
+        // nothing was received yet, the executor stops once the closed receivers and the
+        // handle dropped every sender of its channels
+        close_receivers(receivers).await;
+        executor_handle.wait().await;
+        acker_handle.wait().await;
+        return Err(e);
+    }
+
+    // since there is no channels initialized in the consumer actor, its unecessary to create a handle so just init receiver task
+    let mut receiver_tasks = Vec::with_capacity(receivers.len());
+    for mut receiver in receivers {
+        let receiver_shutdown = shutdown.clone();
+        // tracing's `Instrument` would shadow the monitor method
+        let task = TaskMonitor::instrument(&Metrics::global().monitors.receiver, async move {
+            if let Err(e) = receiver.consume(receiver_shutdown).await {
+                error!(error = %e, "receiver stopped");
+            }
+            receiver
+        });
+        receiver_tasks.push(tokio::spawn(task));
+    }
+
+    shutdown.recv().await;
+    info!("shutting down");
+
+    let mut receivers = Vec::with_capacity(receiver_tasks.len());
+    for receiver_task in receiver_tasks {
+        match receiver_task.await {
+            Ok(receiver) => receivers.push(receiver),
+            Err(e) => error!(error = ?e, "receiver task failed"),
+        }
+    }
+
+    executor_handle.wait().await;
+
+    acker_handle.wait().await;
+
+    close_receivers(receivers).await;
+
+    info!("shutdown complete");
+    Ok(())
+}
+
+/// Closes the consumers, their unacked messages are redelivered to the other ones
+async fn close_receivers<S: Subscription<TestData>>(receivers: Vec<actors::Receiver<TestData, S>>) {
+    for receiver in receivers {
+        if let Err(e) = receiver.close().await {
+            error!(error = %e, "closing receiver failed");
+        }
+    }
+}
+
+/// Prints the effective config and where every configured topic lives on the cluster
+pub async fn inspect(config: &Config) -> Result<()> {
+    println!("{:#?}", config);
+
+    let broker = PulsarBroker::connect(&config.pulsar.addr).await?;
+    let pulsar = broker.client();
+
+    let topics: BTreeSet<&String> = config
+        .producer
+        .topics
+        .iter()
+        .chain(config.consumer.topics.names())
+        .collect();
+
+    for topic in topics {
+        match pulsar.lookup_partitioned_topic(topic.as_str()).await {
+            Ok(partitions) => {
+                println!("topic {} => {} partition(s)", topic, partitions.len());
+                for (partition, address) in partitions {
+                    println!("    {} on broker {}", partition, address.broker_url);
+                }
+            }
+            Err(e) => eprintln!("topic {} => lookup failed: {}", topic, e),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use std::{num::NonZeroUsize, sync::Arc, thread::available_parallelism};
+
+use tokio::{sync::Semaphore, task::spawn_blocking};
+use tracing::{info_span, Span};
+
+use crate::{
+    handler::HandlerError,
+    metrics::{GaugeGuard, Metrics},
+};
+
+/// Bounded pool running the CPU bound stages of the handlers
+///
+/// closures run on tokio's blocking threads, never on the workers polling the actors, and at
+/// most `concurrency` of them at a time whatever the executors concurrency. Clones share
+/// the same budget
+#[derive(Clone)]
+pub struct ComputePool {
+    semaphore: Arc<Semaphore>,
+}
+
+impl ComputePool {
+    /// `concurrency` closures at a time, 0 for as many as the available CPUs
+    pub fn new(concurrency: usize) -> Self {
+        let concurrency = match concurrency {
+            0 => available_parallelism().map_or(1, NonZeroUsize::get),
+            concurrency => concurrency,
+        };
+
+        Self {
+            semaphore: Arc::new(Semaphore::new(concurrency)),
+        }
+    }
+
+    /// Runs `f` on the pool once a slot is free, within a `compute` span of the current one
+    ///
+    /// a panic fails the message with a retryable error. The closure keeps running if the
+    /// caller is dropped meanwhile, e.g. when the executor aborts its tasks on shutdown
+    pub async fn run<F, R>(&self, f: F) -> Result<R, HandlerError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        // the semaphore is owned by the pool and never closed
+        let waiting = GaugeGuard::inc(&Metrics::global().compute_waiting);
+        let permit = self
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| HandlerError::Retryable(format!("compute pool closed: {}", e)))?;
+        drop(waiting);
+
+        let span = info_span!(parent: Span::current(), "compute");
+        let task = spawn_blocking(move || {
+            let _running = GaugeGuard::inc(&Metrics::global().compute_in_use);
+            let result = span.in_scope(f);
+            drop(permit);
+            result
+        });
+
+        task.await
+            .map_err(|e| HandlerError::Retryable(format!("compute stage panicked: {:?}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use futures::future::join_all;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn runs_at_most_concurrency_closures_at_once() {
+        let pool = ComputePool::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let runs = (0..8).map(|i| {
+            let (running, peak) = (running.clone(), peak.clone());
+            pool.run(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                i
+            })
+        });
+        let results: Vec<_> = join_all(runs).await.into_iter().map(Result::unwrap).collect();
+
+        assert_eq!(results, (0..8).collect::<Vec<_>>());
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn panic_fails_with_a_retryable_error() {
+        let pool = ComputePool::new(1);
+
+        let result = pool.run(|| panic!("compute bug")).await;
+
+        assert!(matches!(result, Err(HandlerError::Retryable(reason)) if reason.contains("panicked")));
+        // the permit was released
+        assert_eq!(pool.run(|| 1).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn runtime_keeps_going_while_the_pool_is_saturated() {
+        // a single thread runtime: a closure run on it would stall every other task
+        let pool = ComputePool::new(1);
+        let finished = Arc::new(AtomicUsize::new(0));
+        for _ in 0..4 {
+            let finished = finished.clone();
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                pool.run(|| thread::sleep(Duration::from_millis(100))).await.unwrap();
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        let started = Instant::now();
+        for _ in 0..10 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert!(started.elapsed() < Duration::from_millis(250));
+        assert!(finished.load(Ordering::SeqCst) < 4);
+    }
+}
@@ -0,0 +1,934 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::{self, Display},
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use pulsar::SubType;
+use serde::{
+    de::{MapAccess, SeqAccess, Visitor},
+    Deserializer,
+};
+
+use crate::{
+    actors::{
+        random_unit, AckMode, ConcurrencyLimit, ExecutionOrder, ExhaustedAction, LimitMode, PipelineConfig, RetryMode,
+        RetryPolicy,
+    },
+    bench::ReportFormat,
+    error::{Error, Result},
+    logging::LogFormat,
+    telemetry::TraceExporter,
+};
+
+/// Config file read when `POC_CONFIG` is not set, defaults are used if it does not exist
+pub const DEFAULT_CONFIG_PATH: &str = "config/poc.toml";
+
+/// Settings shared by every subcommand
+///
+/// loaded from a TOML file, then every `POC_*` environment variable listed in
+/// `Config::apply_env` overrides the matching field
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub pulsar: PulsarConfig,
+    pub producer: ProducerConfig,
+    pub consumer: ConsumerConfig,
+    pub metrics: MetricsConfig,
+    pub log: LogConfig,
+    pub trace: TraceConfig,
+    pub bench: BenchConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PulsarConfig {
+    pub addr: String,
+}
+
+impl Default for PulsarConfig {
+    fn default() -> Self {
+        Self {
+            addr: "pulsar://127.0.0.1:6650".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `EnvFilter` directives, e.g. `info,pulsar_rust_poc::actors=debug`
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+/// Export of the message traces, the W3C trace context travels in the message properties
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TraceConfig {
+    pub exporter: TraceExporter,
+    /// gRPC endpoint of the OTLP collector
+    pub otlp_endpoint: String,
+    /// spans are appended to it with the file exporter
+    pub file: PathBuf,
+    pub service_name: String,
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        Self {
+            exporter: TraceExporter::None,
+            otlp_endpoint: "http://127.0.0.1:4317".to_string(),
+            file: PathBuf::from("traces.jsonl"),
+            service_name: "pulsar-rust-poc".to_string(),
+        }
+    }
+}
+
+/// Prometheus endpoint of the consume and bench subcommands
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// listening address of `GET /metrics`
+    pub addr: String,
+    /// how often the runtime and actor task metrics are sampled
+    pub report_interval_ms: u64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addr: "0.0.0.0:9090".to_string(),
+            report_interval_ms: 1000,
+        }
+    }
+}
+
+impl MetricsConfig {
+    pub fn report_interval(&self) -> Duration {
+        Duration::from_millis(self.report_interval_ms.max(1))
+    }
+}
+
+/// Report of the bench subcommand
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BenchConfig {
+    /// JSON reports overwrite it, CSV reports append their rows, to a numbered file next
+    /// to it when its columns differ
+    pub report: PathBuf,
+    pub report_format: ReportFormat,
+    /// consuming stops after it even if some produced messages were not acked
+    pub timeout_secs: u64,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            report: PathBuf::from("bench-report.json"),
+            report_format: ReportFormat::Json,
+            timeout_secs: 300,
+        }
+    }
+}
+
+impl BenchConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProducerConfig {
+    pub name: String,
+    pub topics: Vec<String>,
+    /// messages produced on every topic
+    pub messages_per_topic: usize,
+    /// pulsar producer batch size
+    pub batch_size: u32,
+    pub partition_keys: Vec<String>,
+    /// how the partition key of every message is picked
+    pub key_distribution: KeyDistribution,
+}
+
+impl Default for ProducerConfig {
+    fn default() -> Self {
+        Self {
+            name: "my-producer".to_string(),
+            topics: vec!["test".to_string(), "test-01".to_string()],
+            messages_per_topic: 2000,
+            batch_size: 1000,
+            partition_keys: vec!["10".to_string(), "7".to_string()],
+            key_distribution: KeyDistribution::RoundRobin,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsumerConfig {
+    pub mode: ConsumeMode,
+    /// a list of names, or a `[consumer.topics.<name>]` table per topic with its overrides
+    pub topics: ConsumerTopics,
+    pub consumer_name: String,
+    pub subscription: String,
+    pub subscription_type: SubscriptionType,
+    /// 0 disables the unacked message resend
+    pub unacked_resend_delay_ms: u64,
+    /// maximum handler tasks running at the same time, for both the batch and the actors consumer,
+    /// shared by every topic
+    pub max_concurrency: usize,
+    /// actors consumer: share of the permits of each topic when they compete, 1 if missing
+    pub topic_weights: BTreeMap<String, u32>,
+    /// actors consumer: topics with a higher priority are handled first, 0 if missing
+    pub topic_priorities: BTreeMap<String, u8>,
+    /// actors consumer: share of the permits kept for lower priorities while higher ones are busy
+    pub low_priority_share: f64,
+    /// actors consumer: fixed at `max_concurrency`, or aimd to adjust the limit at runtime
+    pub concurrency_mode: LimitMode,
+    /// actors consumer: lower bound, and starting point, of the aimd limit
+    pub min_concurrency: usize,
+    /// actors consumer: factor applied to the aimd limit on congestion
+    pub concurrency_backoff: f64,
+    /// actors consumer: mean handler latency over the baseline considered congested
+    pub concurrency_latency_tolerance: f64,
+    /// actors consumer: share of failed messages considered congested
+    pub concurrency_error_threshold: f64,
+    /// handler CPU stages running at the same time on the blocking threads, shared by every
+    /// topic, 0 for as many as the available CPUs
+    pub compute_concurrency: usize,
+    /// unordered, or key_ordered to handle the messages of a partition key one at a time
+    pub ordering: ExecutionOrder,
+    /// batch consumer: maximum messages per batch
+    pub batch_size: usize,
+    /// batch consumer: maximum time spent filling a batch
+    pub batch_timeout_ms: u64,
+    /// actors consumer: capacity of the receiver -> executor channel
+    pub executor_queue_capacity: usize,
+    /// actors consumer: capacity of the executor -> acker channel
+    pub acker_queue_capacity: usize,
+    /// actors consumer: time given to in-flight tasks to finish on shutdown
+    pub drain_timeout_ms: u64,
+    /// actors consumer: acks buffered per topic before they are flushed, 1 flushes every ack
+    pub ack_batch_size: usize,
+    /// actors consumer: buffered acks are flushed at least this often
+    pub ack_flush_interval_ms: u64,
+    /// actors consumer: individual, or cumulative for exclusive and failover subscriptions
+    pub ack_mode: AckMode,
+    /// actors consumer: nack failed messages or republish them to `<topic>-RETRY`
+    pub retry_mode: RetryMode,
+    /// deliveries before giving up on a failed message, 0 retries forever. In nack mode the
+    /// attempts are counted by each consumer, a message moving to another consumer starts over
+    pub retry_max_attempts: u32,
+    /// actors consumer: backoff before the first redelivery of a failed message
+    pub retry_base_delay_ms: u64,
+    /// actors consumer: backoff growth factor between attempts
+    pub retry_multiplier: f64,
+    /// actors consumer: backoff upper bound, with the jitter added it must stay below
+    /// `unacked_resend_delay_ms` in nack mode
+    pub retry_max_delay_ms: u64,
+    /// actors consumer: random fraction of the backoff added or removed, 0.0 to 1.0
+    pub retry_jitter: f64,
+    /// what to do with a message once its retries are exhausted or on a permanent error
+    pub retry_exhausted_action: ExhaustedAction,
+    /// empty for pulsar's `<topic>-<subscription>-DLQ`
+    pub dead_letter_topic: String,
+    /// how often the latency percentiles of the last interval are logged, 0 only logs the
+    /// totals once consuming stops
+    pub latency_report_interval_ms: u64,
+}
+
+impl Default for ConsumerConfig {
+    fn default() -> Self {
+        let pipeline = PipelineConfig::default();
+        let retry = pipeline.retry;
+
+        Self {
+            mode: ConsumeMode::Actors,
+            topics: vec!["test".to_string(), "test-01".to_string()].into(),
+            consumer_name: pipeline.consumer_name,
+            subscription: pipeline.subscription,
+            subscription_type: SubscriptionType::KeyShared,
+            unacked_resend_delay_ms: pipeline
+                .unacked_resend_delay
+                .map_or(0, |delay| delay.as_millis() as u64),
+            max_concurrency: pipeline.max_concurrency,
+            topic_weights: pipeline.topic_weights,
+            topic_priorities: pipeline.topic_priorities,
+            low_priority_share: pipeline.low_priority_share,
+            concurrency_mode: pipeline.concurrency.mode,
+            min_concurrency: pipeline.concurrency.min,
+            concurrency_backoff: pipeline.concurrency.backoff,
+            concurrency_latency_tolerance: pipeline.concurrency.latency_tolerance,
+            concurrency_error_threshold: pipeline.concurrency.error_threshold,
+            compute_concurrency: 0,
+            ordering: pipeline.ordering,
+            batch_size: 10000,
+            batch_timeout_ms: 2000,
+            executor_queue_capacity: pipeline.executor_queue_capacity,
+            acker_queue_capacity: pipeline.acker_queue_capacity,
+            drain_timeout_ms: pipeline.drain_timeout.as_millis() as u64,
+            ack_batch_size: pipeline.ack_batch_size,
+            ack_flush_interval_ms: pipeline.ack_flush_interval.as_millis() as u64,
+            ack_mode: pipeline.ack_mode,
+            retry_mode: retry.mode,
+            retry_max_attempts: retry.max_attempts,
+            retry_base_delay_ms: retry.base_delay.as_millis() as u64,
+            retry_multiplier: retry.multiplier,
+            retry_max_delay_ms: retry.max_delay.as_millis() as u64,
+            retry_jitter: retry.jitter,
+            retry_exhausted_action: retry.exhausted_action,
+            dead_letter_topic: pipeline.dead_letter_topic.unwrap_or_default(),
+            latency_report_interval_ms: 10000,
+        }
+    }
+}
+
+impl ConsumerConfig {
+    pub fn batch_timeout(&self) -> Duration {
+        Duration::from_millis(self.batch_timeout_ms)
+    }
+
+    pub fn unacked_resend_delay(&self) -> Option<Duration> {
+        (self.unacked_resend_delay_ms > 0).then(|| Duration::from_millis(self.unacked_resend_delay_ms))
+    }
+
+    pub fn latency_report_interval(&self) -> Option<Duration> {
+        (self.latency_report_interval_ms > 0).then(|| Duration::from_millis(self.latency_report_interval_ms))
+    }
+
+    /// Pipeline settings for the actors consumer, shared by the executor and the acker
+    pub fn pipeline(&self) -> PipelineConfig {
+        let retry = self.retry();
+        let topic_retries = self
+            .topics
+            .overrides
+            .iter()
+            .map(|(topic, overrides)| (topic.clone(), overrides.retry(&retry)))
+            .collect();
+
+        PipelineConfig {
+            max_concurrency: self.max_concurrency,
+            topic_weights: self.topic_weights.clone(),
+            topic_priorities: self.topic_priorities.clone(),
+            low_priority_share: self.low_priority_share,
+            concurrency: self.concurrency(),
+            ordering: self.ordering,
+            executor_queue_capacity: self.executor_queue_capacity,
+            acker_queue_capacity: self.acker_queue_capacity,
+            drain_timeout: Duration::from_millis(self.drain_timeout_ms),
+            consumer_name: self.consumer_name.clone(),
+            subscription: self.subscription.clone(),
+            subscription_type: self.subscription_type.into(),
+            unacked_resend_delay: self.unacked_resend_delay(),
+            retry,
+            topic_retries,
+            dead_letter_topic: self.dead_letter_topic(),
+            ack_batch_size: self.ack_batch_size,
+            ack_flush_interval: Duration::from_millis(self.ack_flush_interval_ms),
+            ack_mode: self.ack_mode,
+        }
+    }
+
+    /// Pipeline settings of the receivers of `topic`, with its overrides applied
+    pub fn topic_pipeline(&self, topic: &str) -> PipelineConfig {
+        let mut pipeline = self.pipeline();
+        pipeline.retry = pipeline.topic_retry(topic).clone();
+        if let Some(overrides) = self.topics.overrides.get(topic) {
+            overrides.apply(&mut pipeline);
+        }
+        pipeline
+    }
+
+    pub fn dead_letter_topic(&self) -> Option<String> {
+        (!self.dead_letter_topic.is_empty()).then(|| self.dead_letter_topic.clone())
+    }
+
+    pub fn concurrency(&self) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            mode: self.concurrency_mode,
+            min: self.min_concurrency,
+            backoff: self.concurrency_backoff,
+            latency_tolerance: self.concurrency_latency_tolerance,
+            error_threshold: self.concurrency_error_threshold,
+        }
+    }
+
+    pub fn retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            mode: self.retry_mode,
+            max_attempts: self.retry_max_attempts,
+            base_delay: Duration::from_millis(self.retry_base_delay_ms),
+            multiplier: self.retry_multiplier,
+            max_delay: Duration::from_millis(self.retry_max_delay_ms),
+            jitter: self.retry_jitter,
+            exhausted_action: self.retry_exhausted_action,
+        }
+    }
+}
+
+/// Consumed topics, in the configured order, with their overrides of the consumer settings
+///
+/// deserialized from a list of names, `topics = ["test", "test-01"]`, or from a table per
+/// topic, `[consumer.topics.test]` followed by the overridden fields
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConsumerTopics {
+    names: Vec<String>,
+    overrides: BTreeMap<String, TopicOverrides>,
+}
+
+impl ConsumerTopics {
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn overrides(&self, topic: &str) -> Option<&TopicOverrides> {
+        self.overrides.get(topic)
+    }
+
+    /// Consumes `names` instead, the overrides of the topics kept still apply
+    pub fn set_names(&mut self, names: Vec<String>) {
+        self.names = names;
+    }
+}
+
+impl From<Vec<String>> for ConsumerTopics {
+    fn from(names: Vec<String>) -> Self {
+        Self {
+            names,
+            overrides: BTreeMap::new(),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ConsumerTopics {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct TopicsVisitor;
+
+        impl<'de> Visitor<'de> for TopicsVisitor {
+            type Value = ConsumerTopics;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a list of topics or a table of topic overrides")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error> {
+                let mut names = Vec::new();
+                while let Some(name) = seq.next_element()? {
+                    names.push(name);
+                }
+                Ok(names.into())
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<Self::Value, A::Error> {
+                let mut topics = ConsumerTopics::default();
+                while let Some((name, overrides)) = map.next_entry::<String, TopicOverrides>()? {
+                    topics.names.push(name.clone());
+                    topics.overrides.insert(name, overrides);
+                }
+                Ok(topics)
+            }
+        }
+
+        deserializer.deserialize_any(TopicsVisitor)
+    }
+}
+
+/// Consumer settings of one topic, each field set replaces the `[consumer]` one for it
+///
+/// actors consumer only: they apply to the receivers of the topic and to the backoff of its
+/// failed messages, the batch consumer reads every topic with one subscription
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TopicOverrides {
+    pub subscription_type: Option<SubscriptionType>,
+    pub ack_mode: Option<AckMode>,
+    /// 0 disables the unacked message resend
+    pub unacked_resend_delay_ms: Option<u64>,
+    pub retry_base_delay_ms: Option<u64>,
+    pub retry_max_delay_ms: Option<u64>,
+}
+
+impl TopicOverrides {
+    /// `retry` with the backoff of this topic
+    pub fn retry(&self, retry: &RetryPolicy) -> RetryPolicy {
+        RetryPolicy {
+            base_delay: self.retry_base_delay_ms.map_or(retry.base_delay, Duration::from_millis),
+            max_delay: self.retry_max_delay_ms.map_or(retry.max_delay, Duration::from_millis),
+            ..retry.clone()
+        }
+    }
+
+    fn apply(&self, pipeline: &mut PipelineConfig) {
+        if let Some(subscription_type) = self.subscription_type {
+            pipeline.subscription_type = subscription_type.into();
+        }
+        if let Some(ack_mode) = self.ack_mode {
+            pipeline.ack_mode = ack_mode;
+        }
+        if let Some(delay_ms) = self.unacked_resend_delay_ms {
+            pipeline.unacked_resend_delay = (delay_ms > 0).then(|| Duration::from_millis(delay_ms));
+        }
+    }
+}
+
+/// How messages are consumed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsumeMode {
+    /// `BatchConsumer`: N messages or a timeout, then the whole batch is handled
+    Batch,
+    /// Receiver -> Executor -> Acker actors pipeline per topic
+    Actors,
+}
+
+impl FromStr for ConsumeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "batch" => Ok(ConsumeMode::Batch),
+            "actors" => Ok(ConsumeMode::Actors),
+            _ => Err(format!("unknown consume mode {:?}, expected batch or actors", s)),
+        }
+    }
+}
+
+/// Partition keys of the produced messages, picked among `ProducerConfig::partition_keys`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyDistribution {
+    /// every key in turn, each one gets the same share
+    RoundRobin,
+    /// a random key, each one gets about the same share
+    Uniform,
+    /// a random key, Zipf like: the i-th key gets about 1 / i of the share of the first one
+    Skewed,
+}
+
+impl KeyDistribution {
+    /// Index of the key of the `i`-th message among `keys`, `None` without keys
+    pub fn pick(self, i: usize, keys: usize) -> Option<usize> {
+        if keys == 0 {
+            return None;
+        }
+
+        let index = match self {
+            KeyDistribution::RoundRobin => i % keys,
+            KeyDistribution::Uniform => (random_unit() * keys as f64) as usize,
+            // (keys + 1)^u - 1 falls in [i, i + 1) with a probability of ln((i + 2) / (i + 1))
+            KeyDistribution::Skewed => ((keys as f64 + 1.0).powf(random_unit()) - 1.0) as usize,
+        };
+        Some(index.min(keys - 1))
+    }
+}
+
+impl FromStr for KeyDistribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(KeyDistribution::RoundRobin),
+            "uniform" => Ok(KeyDistribution::Uniform),
+            "skewed" => Ok(KeyDistribution::Skewed),
+            _ => Err(format!(
+                "unknown key distribution {:?}, expected round_robin, uniform or skewed",
+                s
+            )),
+        }
+    }
+}
+
+/// Serializable mirror of pulsar `SubType`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionType {
+    Exclusive,
+    Shared,
+    Failover,
+    KeyShared,
+}
+
+impl From<SubscriptionType> for SubType {
+    fn from(sub_type: SubscriptionType) -> Self {
+        match sub_type {
+            SubscriptionType::Exclusive => SubType::Exclusive,
+            SubscriptionType::Shared => SubType::Shared,
+            SubscriptionType::Failover => SubType::Failover,
+            SubscriptionType::KeyShared => SubType::KeyShared,
+        }
+    }
+}
+
+impl FromStr for SubscriptionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "exclusive" => Ok(SubscriptionType::Exclusive),
+            "shared" => Ok(SubscriptionType::Shared),
+            "failover" => Ok(SubscriptionType::Failover),
+            "key_shared" => Ok(SubscriptionType::KeyShared),
+            _ => Err(format!(
+                "unknown subscription type {:?}, expected exclusive, shared, failover or key_shared",
+                s
+            )),
+        }
+    }
+}
+
+impl Config {
+    /// Loads `path`, or the file pointed by `POC_CONFIG` (or `DEFAULT_CONFIG_PATH`),
+    /// then applies env overrides
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut config = match (path, env::var("POC_CONFIG")) {
+            (Some(path), _) => Self::from_file(path)?,
+            (None, Ok(path)) => Self::from_file(path)?,
+            (None, Err(_)) if Path::new(DEFAULT_CONFIG_PATH).exists() => Self::from_file(DEFAULT_CONFIG_PATH)?,
+            (None, Err(_)) => Self::default(),
+        };
+
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("reading {}: {}", path.display(), e)))?;
+
+        toml::from_str(&content).map_err(|e| Error::Config(format!("parsing {}: {}", path.display(), e)))
+    }
+
+    /// Overrides fields with the `POC_*` environment variables that are set
+    ///
+    /// lists (topics, partition keys) are comma separated
+    pub fn apply_env(&mut self) -> Result<()> {
+        env_override(&mut self.pulsar.addr, "POC_PULSAR_ADDR")?;
+
+        env_override(&mut self.producer.name, "POC_PRODUCER_NAME")?;
+        env_override_list(&mut self.producer.topics, "POC_PRODUCER_TOPICS");
+        env_override(&mut self.producer.messages_per_topic, "POC_PRODUCER_MESSAGES_PER_TOPIC")?;
+        env_override(&mut self.producer.batch_size, "POC_PRODUCER_BATCH_SIZE")?;
+        env_override_list(&mut self.producer.partition_keys, "POC_PRODUCER_PARTITION_KEYS");
+        env_override(&mut self.producer.key_distribution, "POC_PRODUCER_KEY_DISTRIBUTION")?;
+
+        env_override(&mut self.consumer.mode, "POC_CONSUMER_MODE")?;
+        let mut topics = self.consumer.topics.names().to_vec();
+        env_override_list(&mut topics, "POC_CONSUMER_TOPICS");
+        self.consumer.topics.set_names(topics);
+        env_override(&mut self.consumer.consumer_name, "POC_CONSUMER_NAME")?;
+        env_override(&mut self.consumer.subscription, "POC_CONSUMER_SUBSCRIPTION")?;
+        env_override(&mut self.consumer.subscription_type, "POC_CONSUMER_SUBSCRIPTION_TYPE")?;
+        env_override(&mut self.consumer.unacked_resend_delay_ms, "POC_CONSUMER_UNACKED_RESEND_DELAY_MS")?;
+        env_override(&mut self.consumer.max_concurrency, "POC_CONSUMER_MAX_CONCURRENCY")?;
+        env_override_map(&mut self.consumer.topic_weights, "POC_CONSUMER_TOPIC_WEIGHTS")?;
+        env_override_map(&mut self.consumer.topic_priorities, "POC_CONSUMER_TOPIC_PRIORITIES")?;
+        env_override(&mut self.consumer.low_priority_share, "POC_CONSUMER_LOW_PRIORITY_SHARE")?;
+        env_override(&mut self.consumer.concurrency_mode, "POC_CONSUMER_CONCURRENCY_MODE")?;
+        env_override(&mut self.consumer.min_concurrency, "POC_CONSUMER_MIN_CONCURRENCY")?;
+        env_override(&mut self.consumer.concurrency_backoff, "POC_CONSUMER_CONCURRENCY_BACKOFF")?;
+        env_override(
+            &mut self.consumer.concurrency_latency_tolerance,
+            "POC_CONSUMER_CONCURRENCY_LATENCY_TOLERANCE",
+        )?;
+        env_override(
+            &mut self.consumer.concurrency_error_threshold,
+            "POC_CONSUMER_CONCURRENCY_ERROR_THRESHOLD",
+        )?;
+        env_override(&mut self.consumer.compute_concurrency, "POC_CONSUMER_COMPUTE_CONCURRENCY")?;
+        env_override(&mut self.consumer.ordering, "POC_CONSUMER_ORDERING")?;
+        env_override(&mut self.consumer.batch_size, "POC_CONSUMER_BATCH_SIZE")?;
+        env_override(&mut self.consumer.batch_timeout_ms, "POC_CONSUMER_BATCH_TIMEOUT_MS")?;
+        env_override(&mut self.consumer.executor_queue_capacity, "POC_CONSUMER_EXECUTOR_QUEUE_CAPACITY")?;
+        env_override(&mut self.consumer.acker_queue_capacity, "POC_CONSUMER_ACKER_QUEUE_CAPACITY")?;
+        env_override(&mut self.consumer.drain_timeout_ms, "POC_CONSUMER_DRAIN_TIMEOUT_MS")?;
+        env_override(&mut self.consumer.ack_batch_size, "POC_CONSUMER_ACK_BATCH_SIZE")?;
+        env_override(&mut self.consumer.ack_flush_interval_ms, "POC_CONSUMER_ACK_FLUSH_INTERVAL_MS")?;
+        env_override(&mut self.consumer.ack_mode, "POC_CONSUMER_ACK_MODE")?;
+        env_override(&mut self.consumer.retry_mode, "POC_CONSUMER_RETRY_MODE")?;
+        env_override(&mut self.consumer.retry_max_attempts, "POC_CONSUMER_RETRY_MAX_ATTEMPTS")?;
+        env_override(&mut self.consumer.retry_base_delay_ms, "POC_CONSUMER_RETRY_BASE_DELAY_MS")?;
+        env_override(&mut self.consumer.retry_multiplier, "POC_CONSUMER_RETRY_MULTIPLIER")?;
+        env_override(&mut self.consumer.retry_max_delay_ms, "POC_CONSUMER_RETRY_MAX_DELAY_MS")?;
+        env_override(&mut self.consumer.retry_jitter, "POC_CONSUMER_RETRY_JITTER")?;
+        env_override(&mut self.consumer.retry_exhausted_action, "POC_CONSUMER_RETRY_EXHAUSTED_ACTION")?;
+        env_override(&mut self.consumer.dead_letter_topic, "POC_CONSUMER_DEAD_LETTER_TOPIC")?;
+        env_override(
+            &mut self.consumer.latency_report_interval_ms,
+            "POC_CONSUMER_LATENCY_REPORT_INTERVAL_MS",
+        )?;
+
+        env_override(&mut self.metrics.enabled, "POC_METRICS_ENABLED")?;
+        env_override(&mut self.metrics.addr, "POC_METRICS_ADDR")?;
+        env_override(&mut self.metrics.report_interval_ms, "POC_METRICS_REPORT_INTERVAL_MS")?;
+
+        env_override(&mut self.log.level, "POC_LOG_LEVEL")?;
+        env_override(&mut self.log.format, "POC_LOG_FORMAT")?;
+
+        env_override(&mut self.trace.exporter, "POC_TRACE_EXPORTER")?;
+        env_override(&mut self.trace.otlp_endpoint, "POC_TRACE_OTLP_ENDPOINT")?;
+        env_override(&mut self.trace.file, "POC_TRACE_FILE")?;
+        env_override(&mut self.trace.service_name, "POC_TRACE_SERVICE_NAME")?;
+
+        env_override(&mut self.bench.report, "POC_BENCH_REPORT")?;
+        env_override(&mut self.bench.report_format, "POC_BENCH_REPORT_FORMAT")?;
+        env_override(&mut self.bench.timeout_secs, "POC_BENCH_TIMEOUT_SECS")?;
+
+        Ok(())
+    }
+}
+
+fn env_override<V>(target: &mut V, key: &str) -> Result<()>
+where
+    V: FromStr,
+    V::Err: Display,
+{
+    if let Ok(value) = env::var(key) {
+        *target = value
+            .parse()
+            .map_err(|e| Error::Config(format!("{}={:?}: {}", key, value, e)))?;
+    }
+    Ok(())
+}
+
+/// `name=value` pairs separated by commas, e.g. `test=3,test-01=1`
+fn env_override_map<V>(target: &mut BTreeMap<String, V>, key: &str) -> Result<()>
+where
+    V: FromStr,
+    V::Err: Display,
+{
+    if let Ok(value) = env::var(key) {
+        let invalid = |reason: String| Error::Config(format!("{}={:?}: {}", key, value, reason));
+
+        let mut map = BTreeMap::new();
+        for pair in value.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (name, item) = pair
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("{:?} is not a name=value pair", pair)))?;
+            let item = item.trim().parse().map_err(|e| invalid(format!("{:?}: {}", pair, e)))?;
+            map.insert(name.trim().to_string(), item);
+        }
+        *target = map;
+    }
+    Ok(())
+}
+
+fn env_override_list(target: &mut Vec<String>, key: &str) {
+    if let Ok(value) = env::var(key) {
+        *target = value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // the process environment is shared by the tests running in parallel
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn parse(toml: &str) -> Result<Config> {
+        toml::from_str(toml).map_err(|e| Error::Config(e.to_string()))
+    }
+
+    /// Applies the env overrides with `vars` set, then removes them
+    fn with_env(config: &mut Config, vars: &[(&str, &str)]) -> Result<()> {
+        let _guard = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (key, value) in vars {
+            env::set_var(key, value);
+        }
+        let applied = config.apply_env();
+        for (key, _) in vars {
+            env::remove_var(key);
+        }
+        applied
+    }
+
+    #[test]
+    fn shipped_config_parses() {
+        let config = Config::from_file(Path::new(env!("CARGO_MANIFEST_DIR")).join(DEFAULT_CONFIG_PATH)).unwrap();
+
+        assert_eq!(config.consumer.mode, ConsumeMode::Actors);
+        assert_eq!(config.consumer.topics.names(), ["test", "test-01"]);
+        assert_eq!(config.consumer.subscription_type, SubscriptionType::KeyShared);
+        assert_eq!(config.consumer.unacked_resend_delay(), Some(Duration::from_secs(60)));
+        assert_eq!(config.producer.key_distribution, KeyDistribution::RoundRobin);
+        assert_eq!(config.bench.report_format, ReportFormat::Json);
+        assert_eq!(config.consumer.retry().max_attempts, RetryPolicy::default().max_attempts);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let parsed = parse(
+            r#"
+            [consumer]
+            max_concurency = 10
+            "#,
+        );
+
+        assert!(matches!(parsed, Err(Error::Config(e)) if e.contains("max_concurency")));
+    }
+
+    #[test]
+    fn env_overrides_scalars_and_lists() {
+        let mut config = Config::default();
+
+        with_env(
+            &mut config,
+            &[
+                ("POC_CONSUMER_MAX_CONCURRENCY", "7"),
+                ("POC_CONSUMER_MODE", "batch"),
+                ("POC_CONSUMER_TOPICS", " a, b ,,c "),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.consumer.max_concurrency, 7);
+        assert_eq!(config.consumer.mode, ConsumeMode::Batch);
+        assert_eq!(config.consumer.topics.names(), ["a", "b", "c"]);
+        // left alone when not set
+        assert_eq!(config.producer.topics, ["test", "test-01"]);
+    }
+
+    #[test]
+    fn env_list_keeps_the_overrides_of_the_topics() {
+        let mut config = parse(
+            r#"
+            [consumer.topics.a]
+            ack_mode = "cumulative"
+            "#,
+        )
+        .unwrap();
+
+        with_env(&mut config, &[("POC_CONSUMER_TOPICS", "a,b")]).unwrap();
+
+        assert_eq!(config.consumer.topics.names(), ["a", "b"]);
+        assert_eq!(config.consumer.topic_pipeline("a").ack_mode, AckMode::Cumulative);
+        assert_eq!(config.consumer.topic_pipeline("b").ack_mode, AckMode::Individual);
+    }
+
+    #[test]
+    fn env_overrides_maps() {
+        let mut weights = BTreeMap::from([("old".to_string(), 9)]);
+        let key = "POC_TEST_ENV_OVERRIDE_MAP";
+
+        let _guard = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        env::set_var(key, "test=3, test-01 = 1,");
+        let applied = env_override_map::<u32>(&mut weights, key);
+        env::set_var(key, "test:3");
+        let invalid = env_override_map::<u32>(&mut weights, key);
+        env::remove_var(key);
+
+        applied.unwrap();
+        assert_eq!(weights, BTreeMap::from([("test".to_string(), 3), ("test-01".to_string(), 1)]));
+        assert!(matches!(invalid, Err(Error::Config(e)) if e.contains("name=value")));
+    }
+
+    #[test]
+    fn invalid_env_values_are_errors() {
+        let mut config = Config::default();
+
+        let applied = with_env(&mut config, &[("POC_CONSUMER_MAX_CONCURRENCY", "lots")]);
+        assert!(matches!(applied, Err(Error::Config(e)) if e.contains("POC_CONSUMER_MAX_CONCURRENCY")));
+
+        let applied = with_env(&mut config, &[("POC_CONSUMER_ACK_MODE", "sometimes")]);
+        assert!(matches!(applied, Err(Error::Config(e)) if e.contains("unknown ack mode")));
+        assert_eq!(config.consumer.ack_mode, AckMode::Individual);
+    }
+
+    #[test]
+    fn topic_tables_override_their_receivers_only() {
+        let config = parse(
+            r#"
+            [consumer]
+            subscription_type = "key_shared"
+            unacked_resend_delay_ms = 60000
+            retry_base_delay_ms = 1000
+            retry_max_delay_ms = 30000
+
+            [consumer.topics.test]
+
+            [consumer.topics."test-01"]
+            subscription_type = "failover"
+            ack_mode = "cumulative"
+            unacked_resend_delay_ms = 0
+            retry_base_delay_ms = 10
+            retry_max_delay_ms = 100
+            "#,
+        )
+        .unwrap();
+        let consumer = &config.consumer;
+        assert_eq!(consumer.topics.names(), ["test", "test-01"]);
+
+        let test = consumer.topic_pipeline("test");
+        assert_eq!(test.subscription_type, SubType::KeyShared);
+        assert_eq!(test.ack_mode, AckMode::Individual);
+        assert_eq!(test.unacked_resend_delay, Some(Duration::from_secs(60)));
+        assert_eq!(test.retry.base_delay, Duration::from_secs(1));
+
+        let tuned = consumer.topic_pipeline("test-01");
+        assert_eq!(tuned.subscription_type, SubType::Failover);
+        assert_eq!(tuned.ack_mode, AckMode::Cumulative);
+        assert_eq!(tuned.unacked_resend_delay, None);
+        assert_eq!(tuned.retry.base_delay, Duration::from_millis(10));
+        assert_eq!(tuned.retry.max_delay, Duration::from_millis(100));
+
+        // the shared acker backs off each topic with its own delays
+        let shared = consumer.pipeline();
+        assert_eq!(shared.retry.base_delay, Duration::from_secs(1));
+        assert_eq!(shared.topic_retry("test").base_delay, Duration::from_secs(1));
+        assert_eq!(shared.topic_retry("test-01").base_delay, Duration::from_millis(10));
+        assert_eq!(shared.subscription_type, SubType::KeyShared);
+    }
+
+    #[test]
+    fn topic_list_has_no_overrides() {
+        let config = parse(
+            r#"
+            [consumer]
+            topics = ["a", "b"]
+            ack_mode = "cumulative"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.consumer.topics.names(), ["a", "b"]);
+        assert!(config.consumer.topics.overrides("a").is_none());
+        assert_eq!(config.consumer.topic_pipeline("a").ack_mode, AckMode::Cumulative);
+    }
+
+    #[test]
+    fn topic_overrides_reject_unknown_fields() {
+        let parsed = parse(
+            r#"
+            [consumer.topics.test]
+            ack_mod = "cumulative"
+            "#,
+        );
+
+        assert!(matches!(parsed, Err(Error::Config(e)) if e.contains("ack_mod")));
+    }
+}
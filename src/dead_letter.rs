@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use pulsar::producer;
+
+use crate::{
+    broker::Broker,
+    error::Result,
+    handler::HandlerError,
+    message::{Message, ORIGIN_MESSAGE_ID, REAL_TOPIC},
+};
+
+/// Property holding the last handler error
+pub const ERROR: &str = "ERROR";
+/// Property holding how many deliveries failed
+pub const ATTEMPTS: &str = "ATTEMPTS";
+
+/// Republishes the messages that will not be retried to a dead letter topic
+///
+/// the original payload, key and properties are kept, `REAL_TOPIC`, `ORIGIN_MESSAGE_ID` and
+/// the properties above are added so the message can be inspected or replayed. The caller
+/// acks the original once published
+#[derive(Clone)]
+pub struct DeadLetter<B> {
+    broker: B,
+    topic: Option<String>,
+    subscription: String,
+}
+
+impl<B: Broker> DeadLetter<B> {
+    /// `topic` defaults to pulsar's `<topic>-<subscription>-DLQ` for each origin topic
+    pub fn new(broker: B, topic: Option<String>, subscription: String) -> Self {
+        Self {
+            broker,
+            topic,
+            subscription,
+        }
+    }
+
+    pub fn topic_for(&self, original_topic: &str) -> String {
+        match &self.topic {
+            Some(topic) => topic.clone(),
+            None => format!("{}-{}-DLQ", original_topic, self.subscription),
+        }
+    }
+
+    pub async fn publish<T>(&self, msg: &Message<T>, error: &HandlerError, attempts: u32) -> Result<()> {
+        let message = letter_message(msg, error, [(ATTEMPTS, attempts.to_string())]);
+
+        self.broker.produce(&self.topic_for(msg.origin_topic()), message).await
+    }
+}
+
+/// Copy of `msg` to republish as a dead or retry letter
+///
+/// payload, keys, event time and properties are kept, `REAL_TOPIC`, `ORIGIN_MESSAGE_ID`,
+/// `ERROR` and `extra_properties` are added
+pub(crate) fn letter_message<T>(
+    msg: &Message<T>,
+    error: &HandlerError,
+    extra_properties: impl IntoIterator<Item = (&'static str, String)>,
+) -> producer::Message {
+    let mut properties: HashMap<String, String> = msg
+        .metadata()
+        .properties
+        .iter()
+        .map(|property| (property.key.clone(), property.value.clone()))
+        .collect();
+    properties.insert(REAL_TOPIC.to_string(), msg.origin_topic().to_string());
+    properties.insert(ORIGIN_MESSAGE_ID.to_string(), msg.origin_message_id());
+    properties.insert(ERROR.to_string(), error.to_string());
+    properties.extend(extra_properties.into_iter().map(|(key, value)| (key.to_string(), value)));
+
+    producer::Message {
+        payload: msg.payload.data.clone(),
+        properties,
+        partition_key: msg.metadata().partition_key.clone(),
+        ordering_key: msg.metadata().ordering_key.clone(),
+        event_time: msg.metadata().event_time,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pulsar::message::proto::KeyValue;
+
+    use super::*;
+    use crate::{message::RECONSUME_TIMES, test_support::message};
+
+    #[test]
+    fn letters_keep_the_original_and_add_their_properties() {
+        let mut msg = message("topic", Some("key"), 3);
+        msg.payload.data = b"payload".to_vec();
+        msg.payload.metadata.properties.push(KeyValue {
+            key: "traceparent".to_string(),
+            value: "00-trace".to_string(),
+        });
+        let error = HandlerError::Retryable("timeout".to_string());
+
+        let letter = letter_message(&msg, &error, [(RECONSUME_TIMES, "2".to_string())]);
+
+        assert_eq!(letter.payload, b"payload");
+        assert_eq!(letter.partition_key.as_deref(), Some("key"));
+        let property = |key: &str| letter.properties.get(key).map(String::as_str);
+        assert_eq!(property("traceparent"), Some("00-trace"));
+        assert_eq!(property(REAL_TOPIC), Some("topic"));
+        assert_eq!(property(ORIGIN_MESSAGE_ID), Some("0:3:-1:-1"));
+        assert_eq!(property(ERROR), Some("retryable failure: timeout"));
+        assert_eq!(property(RECONSUME_TIMES), Some("2"));
+        assert_eq!(property(ATTEMPTS), None);
+    }
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+
+use pulsar::error::ConsumerError;
+
+/// Crate level result
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors surfaced by the consumer pipeline
+#[derive(Debug)]
+pub enum Error {
+    /// building the pulsar client, a consumer or a producer failed
+    Build(pulsar::Error),
+    /// reading the next message from the broker failed
+    Consume(pulsar::Error),
+    /// publishing a message failed
+    Produce(pulsar::Error),
+    /// the message payload could not be deserialized
+    Deserialize(String),
+    /// acking or nacking a message failed
+    Ack(ConsumerError),
+    /// the named actor channel was closed, the actor on the other side is gone
+    ChannelClosed(&'static str),
+    /// the broker consumer stream ended, nothing will be delivered anymore
+    ConsumerClosed,
+    /// closing a consumer failed
+    Close(pulsar::Error),
+    /// the configuration file or an environment override is invalid
+    Config(String),
+    /// the metrics endpoint could not be served
+    Metrics(String),
+    /// the trace exporter could not be set up
+    Telemetry(String),
+    /// the bench report could not be written
+    Report(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Build(e) => write!(f, "build failed: {}", e),
+            Error::Consume(e) => write!(f, "consume failed: {}", e),
+            Error::Produce(e) => write!(f, "produce failed: {}", e),
+            Error::Deserialize(e) => write!(f, "deserialization failed: {}", e),
+            Error::Ack(e) => write!(f, "ack failed: {}", e),
+            Error::ChannelClosed(channel) => write!(f, "{} channel closed", channel),
+            Error::ConsumerClosed => write!(f, "consumer stream ended"),
+            Error::Close(e) => write!(f, "close failed: {}", e),
+            Error::Config(e) => write!(f, "invalid config: {}", e),
+            Error::Metrics(e) => write!(f, "metrics endpoint failed: {}", e),
+            Error::Telemetry(e) => write!(f, "trace exporter failed: {}", e),
+            Error::Report(e) => write!(f, "bench report failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Build(e) | Error::Consume(e) | Error::Produce(e) | Error::Close(e) => Some(e),
+            Error::Ack(e) => Some(e),
+            Error::Deserialize(_)
+            | Error::ChannelClosed(_)
+            | Error::ConsumerClosed
+            | Error::Config(_)
+            | Error::Metrics(_)
+            | Error::Telemetry(_)
+            | Error::Report(_) => None,
+        }
+    }
+}
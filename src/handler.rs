@@ -0,0 +1,284 @@
+use std::{collections::HashMap, future::Future, panic::AssertUnwindSafe, sync::Arc, time::Instant};
+
+use futures::FutureExt;
+use pulsar::DeserializeMessage;
+use tokio::sync::Semaphore;
+use tokio_metrics::TaskMonitor;
+use tracing::{error, info_span, Instrument};
+
+use crate::{
+    actors::ExecutionOrder,
+    compute::ComputePool,
+    error::Error,
+    message::Message,
+    metrics::{GaugeGuard, Metrics},
+};
+
+/// Outcome of handling a single message
+pub type HandlerResult = Result<(), HandlerError>;
+
+/// Reasons a handler can fail a message
+#[derive(Debug, Clone)]
+pub enum HandlerError {
+    /// the message may succeed if it is delivered again
+    Retryable(String),
+    /// the message will never succeed, delivering it again is pointless
+    Permanent(String),
+}
+
+impl std::fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandlerError::Retryable(reason) => write!(f, "retryable failure: {}", reason),
+            HandlerError::Permanent(reason) => write!(f, "permanent failure: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+impl From<Error> for HandlerError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Deserialize(_) => HandlerError::Permanent(e.to_string()),
+            _ => HandlerError::Retryable(e.to_string()),
+        }
+    }
+}
+
+/// Business logic run by the executor for every message
+///
+/// the executor turns the returned result into an ack (`Ok`) or a nack (`Err`)
+pub trait Handler<T>: Send + Sync + 'static {
+    fn handle(&self, msg: &Message<T>) -> impl Future<Output = HandlerResult> + Send;
+}
+
+/// Business logic split into async I/O stages around a CPU bound one
+///
+/// `fetch` and `store` run on the runtime like any `Handler`, `compute` runs on the blocking
+/// threads of a `ComputePool` so it can't starve the actors of worker time. Wrap it in a
+/// `PooledHandler` to use it wherever a `Handler` is expected
+pub trait StagedHandler<T>: Send + Sync + 'static {
+    /// what `compute` needs, e.g. the message payload and the dependent data
+    type Fetched: Send + 'static;
+    /// what `store` writes
+    type Computed: Send + 'static;
+
+    fn fetch(&self, msg: &Message<T>) -> impl Future<Output = Result<Self::Fetched, HandlerError>> + Send;
+
+    /// blocking code, must not await nor rely on the tokio runtime
+    fn compute(&self, fetched: Self::Fetched) -> Result<Self::Computed, HandlerError>;
+
+    fn store(&self, msg: &Message<T>, computed: Self::Computed) -> impl Future<Output = HandlerResult> + Send;
+}
+
+/// Adapts a `StagedHandler` into a `Handler`, its CPU stage running on a `ComputePool`
+pub struct PooledHandler<H> {
+    handler: Arc<H>,
+    pool: ComputePool,
+}
+
+impl<H> PooledHandler<H> {
+    pub fn new(handler: H, pool: ComputePool) -> Self {
+        Self {
+            handler: Arc::new(handler),
+            pool,
+        }
+    }
+}
+
+impl<T, H> Handler<T> for PooledHandler<H>
+where
+    T: Sync,
+    H: StagedHandler<T>,
+{
+    async fn handle(&self, msg: &Message<T>) -> HandlerResult {
+        let fetched = self.handler.fetch(msg).await?;
+
+        let handler = self.handler.clone();
+        let computed = self.pool.run(move || handler.compute(fetched)).await??;
+        self.handler.store(msg, computed).await
+    }
+}
+
+/// Business logic run by the batch consumer for a whole batch at once
+///
+/// useful for bulk operations (e.g. a single database write per batch). Hands every message
+/// back with its verdict, in any order, so the batch can be partially acked or nacked. A
+/// message left out is neither acked nor nacked, the broker redelivers it after the unacked
+/// resend delay
+pub trait BatchHandler<T>: Send + Sync + 'static {
+    fn handle_batch(
+        &self,
+        msgs: Vec<Message<T>>,
+    ) -> impl Future<Output = Vec<(Message<T>, HandlerResult)>> + Send;
+}
+
+/// Adapts a per message `Handler` into a `BatchHandler`
+///
+/// every message of the batch runs in its own task, bounded by `max_concurrency`. In
+/// key-ordered mode the messages sharing a partition key run one after the other in a
+/// single task, in batch order
+pub struct ConcurrentBatchHandler<H> {
+    handler: Arc<H>,
+    max_concurrency: usize,
+    ordering: ExecutionOrder,
+}
+
+impl<H> ConcurrentBatchHandler<H> {
+    pub fn new(handler: H, max_concurrency: usize) -> Self {
+        Self {
+            handler: Arc::new(handler),
+            max_concurrency,
+            ordering: ExecutionOrder::Unordered,
+        }
+    }
+
+    pub fn with_ordering(mut self, ordering: ExecutionOrder) -> Self {
+        self.ordering = ordering;
+        self
+    }
+}
+
+impl<T, H> BatchHandler<T> for ConcurrentBatchHandler<H>
+where
+    T: DeserializeMessage + Send + 'static,
+    H: Handler<T>,
+{
+    async fn handle_batch(&self, msgs: Vec<Message<T>>) -> Vec<(Message<T>, HandlerResult)> {
+        let len = msgs.len();
+        let semaphore = Arc::new(Semaphore::new(self.max_concurrency));
+
+        // messages handled one after the other by the same task, with their index in the batch
+        let mut chains: Vec<Vec<(usize, Message<T>)>> = Vec::new();
+        let mut chain_of_key: HashMap<String, usize> = HashMap::new();
+        for (index, msg) in msgs.into_iter().enumerate() {
+            let key = match self.ordering {
+                ExecutionOrder::KeyOrdered => msg.key().map(str::to_string),
+                ExecutionOrder::Unordered => None,
+            };
+            match key {
+                Some(key) => {
+                    let chain = *chain_of_key.entry(key).or_insert_with(|| {
+                        chains.push(Vec::new());
+                        chains.len() - 1
+                    });
+                    chains[chain].push((index, msg));
+                }
+                None => chains.push(vec![(index, msg)]),
+            }
+        }
+
+        let mut tasks = Vec::with_capacity(chains.len());
+        for chain in chains {
+            // the first permit is taken before spawning so at most `max_concurrency` tasks are
+            // spawned ahead, the semaphore is local and never closed
+            let first_permit = semaphore.clone().acquire_owned().await.ok();
+            let semaphore = semaphore.clone();
+            let handler = self.handler.clone();
+
+            let task = async move {
+                let mut results = Vec::with_capacity(chain.len());
+                let mut permit = first_permit;
+                for (index, msg) in chain {
+                    let held = match permit.take() {
+                        Some(permit) => Some(permit),
+                        None => semaphore.clone().acquire_owned().await.ok(),
+                    };
+                    let result = match held {
+                        Some(_) => {
+                            let span = msg.span().clone();
+                            // a panic only fails its message, the task keeps the rest of the chain
+                            AssertUnwindSafe(handle(handler.as_ref(), &msg))
+                                .catch_unwind()
+                                .instrument(span)
+                                .await
+                                .unwrap_or_else(|_| Err(HandlerError::Retryable("handler panicked".to_string())))
+                        }
+                        None => Err(HandlerError::Retryable("message was not handled".to_string())),
+                    };
+                    drop(held);
+                    results.push((index, msg, result));
+                }
+                results
+            };
+            // tracing's `Instrument` would shadow the monitor method
+            let task = TaskMonitor::instrument(&Metrics::global().monitors.executor, task);
+            tasks.push(tokio::spawn(task));
+        }
+
+        let mut handled: Vec<Option<(Message<T>, HandlerResult)>> = (0..len).map(|_| None).collect();
+        for task in tasks {
+            match task.await {
+                Ok(results) => {
+                    for (index, msg, result) in results {
+                        handled[index] = Some((msg, result));
+                    }
+                }
+                // panics are caught per message, the task can only have been cancelled
+                Err(join_err) => error!(error = ?join_err, "batch task failed, its messages will be redelivered"),
+            }
+        }
+        handled.into_iter().flatten().collect()
+    }
+}
+
+/// Runs the handler on a message and records how it went
+async fn handle<T, H: Handler<T>>(handler: &H, msg: &Message<T>) -> HandlerResult {
+    let running = GaugeGuard::inc(&Metrics::global().permits_in_use);
+    let started = Instant::now();
+    let result = handler.handle(msg).instrument(info_span!("handle")).await;
+    Metrics::global().record_handled(&msg.topic, started, &result);
+    drop(running);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{message, Recorder};
+
+    /// Handles entries 0 to 2 of the keys `a` and `b` as one batch, arrival order interleaved
+    ///
+    /// checks the verdicts come back in batch order, the panic of `b` 1 failing only its message
+    async fn handle_keyed(ordering: ExecutionOrder) -> Recorder {
+        let recorder = Recorder::panicking_on("b", 1);
+        let batch_handler = ConcurrentBatchHandler {
+            handler: Arc::new(recorder.clone()),
+            max_concurrency: 8,
+            ordering,
+        };
+
+        let batch: Vec<_> = (0..3).flat_map(|entry| [message("topic", Some("a"), entry), message("topic", Some("b"), entry)]).collect();
+        let handled = batch_handler.handle_batch(batch).await;
+
+        let verdicts: Vec<_> = handled
+            .iter()
+            .map(|(msg, result)| (msg.key().unwrap_or_default(), msg.message_id.entry_id, result.is_ok()))
+            .collect();
+        let expected: Vec<_> = (0..3)
+            .flat_map(|entry| [("a", entry, true), ("b", entry, entry != 1)])
+            .collect();
+        assert_eq!(verdicts, expected);
+        recorder
+    }
+
+    #[tokio::test]
+    async fn batch_messages_of_a_key_overtake_each_other_when_unordered() {
+        let recorder = handle_keyed(ExecutionOrder::Unordered).await;
+
+        assert_eq!(recorder.finished("a"), [2, 1, 0]);
+        assert_eq!(recorder.finished("b"), [2, 1, 0]);
+        assert_eq!(recorder.peak(), 6);
+    }
+
+    #[tokio::test]
+    async fn batch_messages_of_a_key_finish_in_batch_order_when_key_ordered() {
+        let recorder = handle_keyed(ExecutionOrder::KeyOrdered).await;
+
+        assert_eq!(recorder.finished("a"), [0, 1, 2]);
+        // the panic did not stop the rest of the chain
+        assert_eq!(recorder.finished("b"), [0, 1, 2]);
+        assert_eq!(recorder.peak(), 2);
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hdrhistogram::Histogram;
+use tokio::{
+    task::JoinHandle,
+    time::{interval, MissedTickBehavior},
+};
+use tracing::info;
+
+use crate::{message::Message, shutdown::Shutdown};
+
+static LATENCIES: LazyLock<Latencies> = LazyLock::new(Latencies::default);
+
+/// Highest latency recorded, longer ones are clamped to it
+const MAX_LATENCY: Duration = Duration::from_secs(60 * 60);
+
+/// Step of the life of a message whose latency is recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// broker `publish_time` to the delivery, both clocks skew is included
+    PublishToReceive,
+    /// delivery to the handler verdict, executor queueing and batch filling included
+    ReceiveToHandled,
+    /// handler verdict to the ack sent to the broker, buffering and cumulative acks included
+    HandledToAcked,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 3] = [Stage::PublishToReceive, Stage::ReceiveToHandled, Stage::HandledToAcked];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::PublishToReceive => "publish_to_receive",
+            Stage::ReceiveToHandled => "receive_to_handled",
+            Stage::HandledToAcked => "handled_to_acked",
+        }
+    }
+}
+
+/// End-to-end latencies of the consumed messages, an HDR histogram per topic and stage
+///
+/// recorded in microseconds with 3 significant digits, both since the process started and
+/// since the last periodic report
+#[derive(Default)]
+pub struct Latencies {
+    topics: Mutex<HashMap<String, TopicHistograms>>,
+}
+
+/// Histograms of a topic, indexed by stage
+struct TopicHistograms {
+    // since the process started, for the final summary and the bench report
+    total: [Histogram<u64>; 3],
+    // since the last periodic report, reset by it
+    interval: [Histogram<u64>; 3],
+}
+
+impl TopicHistograms {
+    fn new() -> Self {
+        Self {
+            total: std::array::from_fn(|_| new_histogram()),
+            interval: std::array::from_fn(|_| new_histogram()),
+        }
+    }
+}
+
+/// Percentiles of a stage of a topic
+#[derive(Debug, Clone)]
+pub struct LatencySummary {
+    pub topic: String,
+    pub stage: &'static str,
+    pub count: u64,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Latencies {
+    /// Latencies of the process
+    pub fn global() -> &'static Latencies {
+        &LATENCIES
+    }
+
+    pub fn record(&self, topic: &str, stage: Stage, latency: Duration) {
+        let micros = latency.min(MAX_LATENCY).as_micros() as u64;
+
+        let mut topics = self.topics.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !topics.contains_key(topic) {
+            topics.insert(topic.to_string(), TopicHistograms::new());
+        }
+        if let Some(histograms) = topics.get_mut(topic) {
+            histograms.total[stage as usize].saturating_record(micros);
+            histograms.interval[stage as usize].saturating_record(micros);
+        }
+    }
+
+    /// Records the publish to receive latency of a message just delivered
+    ///
+    /// skipped when the broker did not set the publish time
+    pub fn received<T>(&self, msg: &Message<T>) {
+        let publish_time = UNIX_EPOCH + Duration::from_millis(msg.metadata().publish_time);
+        if publish_time == UNIX_EPOCH {
+            return;
+        }
+
+        // a publish time ahead of the local clock counts as no latency
+        let latency = SystemTime::now().duration_since(publish_time).unwrap_or_default();
+        self.record(&msg.topic, Stage::PublishToReceive, latency);
+    }
+
+    /// Percentiles of every topic and stage recorded since the process started, sorted by topic
+    pub fn summary(&self) -> Vec<LatencySummary> {
+        let topics = self.topics.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        summarize(topics.iter().map(|(topic, histograms)| (topic, &histograms.total)))
+    }
+
+    /// Percentiles of every topic and stage recorded since the previous call, then starts
+    /// a new interval
+    pub fn interval_summary(&self) -> Vec<LatencySummary> {
+        let mut topics = self.topics.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let summary = summarize(topics.iter().map(|(topic, histograms)| (topic, &histograms.interval)));
+        for histograms in topics.values_mut() {
+            histograms.interval.iter_mut().for_each(Histogram::reset);
+        }
+        summary
+    }
+
+    /// Logs the summary since the process started, a line per topic and stage
+    pub fn log_summary(&self) {
+        log(&self.summary(), "latency");
+    }
+
+    /// Logs the summary of the last interval and starts a new one
+    pub fn log_interval_summary(&self) {
+        log(&self.interval_summary(), "latency over the last interval");
+    }
+}
+
+fn summarize<'a>(topics: impl Iterator<Item = (&'a String, &'a [Histogram<u64>; 3])>) -> Vec<LatencySummary> {
+    let mut summary: Vec<LatencySummary> = topics
+        .flat_map(|(topic, histograms)| {
+            Stage::ALL.into_iter().filter_map(move |stage| {
+                let histogram = &histograms[stage as usize];
+                (!histogram.is_empty()).then(|| LatencySummary {
+                    topic: topic.clone(),
+                    stage: stage.name(),
+                    count: histogram.len(),
+                    p50: Duration::from_micros(histogram.value_at_quantile(0.5)),
+                    p90: Duration::from_micros(histogram.value_at_quantile(0.9)),
+                    p99: Duration::from_micros(histogram.value_at_quantile(0.99)),
+                    max: Duration::from_micros(histogram.max()),
+                })
+            })
+        })
+        .collect();
+
+    summary.sort_by(|a, b| a.topic.cmp(&b.topic));
+    summary
+}
+
+fn log(summary: &[LatencySummary], message: &str) {
+    for summary in summary {
+        info!(
+            topic = %summary.topic,
+            stage = summary.stage,
+            count = summary.count,
+            p50 = ?summary.p50,
+            p90 = ?summary.p90,
+            p99 = ?summary.p99,
+            max = ?summary.max,
+            "{}",
+            message
+        );
+    }
+}
+
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_LATENCY.as_micros() as u64, 3).expect("valid histogram bounds")
+}
+
+/// Logs the latencies of the last `every` every `every` until shutdown is requested
+///
+/// each report starts a new interval, the totals since the process started are kept
+pub fn report(every: Duration, mut shutdown: Shutdown) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = interval(every);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // the first tick completes right away
+        ticker.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = shutdown.recv() => break,
+                _ = ticker.tick() => Latencies::global().log_interval_summary(),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_summary_resets_but_totals_are_kept() {
+        let latencies = Latencies::default();
+        latencies.record("a", Stage::ReceiveToHandled, Duration::from_millis(10));
+        latencies.record("a", Stage::ReceiveToHandled, Duration::from_millis(20));
+
+        let interval = latencies.interval_summary();
+        assert_eq!(interval.len(), 1);
+        assert_eq!(interval[0].count, 2);
+
+        latencies.record("a", Stage::HandledToAcked, Duration::from_millis(5));
+        let interval = latencies.interval_summary();
+        assert_eq!(interval.len(), 1);
+        assert_eq!(interval[0].stage, Stage::HandledToAcked.name());
+        assert_eq!(interval[0].count, 1);
+        assert!(latencies.interval_summary().is_empty());
+
+        let total: Vec<(&str, u64)> = latencies.summary().iter().map(|s| (s.stage, s.count)).collect();
+        assert_eq!(total, [(Stage::ReceiveToHandled.name(), 2), (Stage::HandledToAcked.name(), 1)]);
+    }
+}
@@ -2,11 +2,36 @@
 extern crate serde;
 
 pub mod actors;
+pub mod batch;
+pub mod bench;
+pub mod broker;
+pub mod commands;
+pub mod compute;
+pub mod config;
+pub mod dead_letter;
+pub mod error;
+pub mod handler;
+pub mod latency;
+pub mod logging;
+pub mod message;
+pub mod metrics;
+pub mod retry_letter;
+pub mod shutdown;
+pub mod system;
+pub mod telemetry;
+
+use std::{collections::HashMap, thread::sleep, time::Duration};
 
 use pulsar::{
     message::Payload,
     producer, DeserializeMessage, Error as PulsarError, SerializeMessage
 };
+use tokio::time::sleep as sleep_tokio;
+use tracing::debug;
+
+use error::Error;
+use handler::{HandlerError, HandlerResult, StagedHandler};
+use message::Message;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TestData {
@@ -18,8 +43,13 @@ impl SerializeMessage for TestData {
     fn serialize_message(input: Self) -> Result<producer::Message, PulsarError> {
         let payload = serde_json::to_vec(&input).map_err(|e| PulsarError::Custom(e.to_string()))?;
 
+        // the trace of the span sending the message
+        let mut properties = HashMap::new();
+        telemetry::inject(&mut properties);
+
         Ok(producer::Message {
             payload,
+            properties,
             partition_key: Some(input.partition_key),
             ..Default::default()
         })
@@ -33,3 +63,130 @@ impl DeserializeMessage for TestData {
         serde_json::from_slice(&payload.data)
     }
 }
+
+/// Handler simulating the PoC workload for `TestData` messages
+///
+/// All the algorithm needs to do is:
+/// 1. Fetch dependent Data (mostly I/O)
+/// 2. Calculate Operations (CPU)
+/// 3. Calculate KPIs (CPU)
+/// 4. Write it into the database (mostly I/O)
+pub struct SimulatedWorkHandler;
+
+impl StagedHandler<TestData> for SimulatedWorkHandler {
+    type Fetched = TestData;
+    type Computed = ();
+
+    async fn fetch(&self, msg: &Message<TestData>) -> Result<TestData, HandlerError> {
+        let data = msg
+            .deserialize()
+            .map_err(|e| Error::Deserialize(e.to_string()))?;
+
+        debug!(?data, "processing data");
+
+        // Simulate some I/O work
+        sleep_tokio(Duration::from_millis(100)).await;
+
+        Ok(data)
+    }
+
+    fn compute(&self, _data: TestData) -> Result<(), HandlerError> {
+        // Simulate some CPU work
+        sleep(Duration::from_millis(10));
+
+        Ok(())
+    }
+
+    async fn store(&self, _msg: &Message<TestData>, _kpis: ()) -> HandlerResult {
+        // the database write is not simulated
+        Ok(())
+    }
+}
+
+/// Fixtures shared by the unit tests
+#[cfg(test)]
+mod test_support {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use pulsar::message::proto::{MessageIdData, MessageMetadata};
+
+    use crate::handler::Handler;
+
+    use super::*;
+
+    /// Message of `topic` at `entry`, with an empty payload
+    pub fn message(topic: &str, key: Option<&str>, entry: u64) -> Message<TestData> {
+        let payload = Payload {
+            metadata: MessageMetadata {
+                partition_key: key.map(str::to_string),
+                ..Default::default()
+            },
+            data: Vec::new(),
+        };
+        let message_id = MessageIdData {
+            entry_id: entry,
+            ..Default::default()
+        };
+        Message::new(topic.to_string(), payload, message_id, 0)
+    }
+
+    /// Handler taking longer on the first entries, so they finish last unless kept in order
+    ///
+    /// records the key and entry of the messages as they finish and the most running at once,
+    /// clones share the records
+    #[derive(Clone, Default)]
+    pub struct Recorder {
+        finished: Arc<Mutex<Vec<(String, u64)>>>,
+        running: Arc<AtomicUsize>,
+        peak: Arc<AtomicUsize>,
+        // key and entry of the message the handler panics on, once it is recorded
+        panics_on: Option<(&'static str, u64)>,
+    }
+
+    impl Handler<TestData> for Recorder {
+        async fn handle(&self, msg: &Message<TestData>) -> HandlerResult {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(running, Ordering::SeqCst);
+
+            let entry = msg.message_id.entry_id;
+            sleep_tokio(Duration::from_millis(20 * (4 - entry.min(3)))).await;
+
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            let key = msg.key().unwrap_or_default().to_string();
+            self.finished.lock().unwrap().push((key, entry));
+            if let Some((key, panic_entry)) = self.panics_on {
+                if msg.key() == Some(key) && entry == panic_entry {
+                    panic!("handler bug");
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Recorder {
+        /// Recorder panicking on the message of `key` at `entry`
+        pub fn panicking_on(key: &'static str, entry: u64) -> Self {
+            Self {
+                panics_on: Some((key, entry)),
+                ..Default::default()
+            }
+        }
+
+        /// Entries of `key` in the order they finished
+        pub fn finished(&self, key: &str) -> Vec<u64> {
+            let finished = self.finished.lock().unwrap();
+            finished.iter().filter(|(k, _)| k == key).map(|(_, entry)| *entry).collect()
+        }
+
+        /// Most messages handled at the same time
+        pub fn peak(&self) -> usize {
+            self.peak.load(Ordering::SeqCst)
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::str::FromStr;
+
+use tracing::Level;
+use tracing_subscriber::{filter::Targets, fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+use crate::{
+    config::LogConfig,
+    error::{Error, Result},
+    telemetry::Telemetry,
+};
+
+/// Output format of the logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// human readable lines, the spans of an event are printed before it
+    Text,
+    /// one JSON object per line, with the fields of the current span and its parents
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}, expected text or json", s)),
+        }
+    }
+}
+
+/// Installs the global tracing subscriber, logs go to stdout
+///
+/// `level` takes `EnvFilter` directives, e.g. `info` or `info,pulsar_rust_poc::actors=debug`.
+/// Per message events are logged at debug level. Records of the `log` crate, used by the
+/// pulsar client, go through the same filter. The info spans of this crate are exported
+/// by `telemetry` whatever the filter. With the `console` feature the tokio-console layer
+/// is added, unfiltered
+pub fn init(config: &LogConfig, telemetry: &Telemetry) -> Result<()> {
+    let filter = EnvFilter::try_new(&config.level)
+        .map_err(|e| Error::Config(format!("log level {:?}: {}", config.level, e)))?;
+
+    let output = match config.format {
+        LogFormat::Text => fmt::layer().boxed(),
+        LogFormat::Json => fmt::layer().json().boxed(),
+    };
+
+    let traces = telemetry.tracer().map(|tracer| {
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::INFO))
+    });
+
+    let subscriber = tracing_subscriber::registry()
+        .with(output.with_filter(filter))
+        .with(traces);
+    #[cfg(feature = "console")]
+    let subscriber = subscriber.with(console_subscriber::spawn());
+
+    subscriber
+        .try_init()
+        .map_err(|e| Error::Config(format!("logging already initialized: {}", e)))
+}
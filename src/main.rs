@@ -0,0 +1,286 @@
+use std::{path::PathBuf, time::Duration};
+
+use clap::{Args, Parser, Subcommand};
+use pulsar_rust_poc::{
+    bench::ReportFormat,
+    commands,
+    config::{ConsumeMode, Config, KeyDistribution},
+    logging::{self, LogFormat},
+    shutdown,
+    system::SystemInfo,
+    telemetry::{Telemetry, TraceExporter},
+};
+use tracing::{error, info, warn};
+
+/// Pulsar Rust client PoC
+///
+/// settings come from the config file, then `POC_*` env vars, then these flags
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// config file, defaults to `POC_CONFIG` or config/poc.toml
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// pulsar broker address
+    #[arg(long, global = true)]
+    pulsar_addr: Option<String>,
+
+    /// log filter directives, e.g. info or info,pulsar_rust_poc::actors=debug
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+
+    /// text or json
+    #[arg(long, global = true)]
+    log_format: Option<LogFormat>,
+
+    /// none, otlp or file
+    #[arg(long, global = true)]
+    trace_exporter: Option<TraceExporter>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Produce TestData messages
+    Produce {
+        /// comma separated topics
+        #[arg(long, value_delimiter = ',')]
+        topics: Option<Vec<String>>,
+
+        #[command(flatten)]
+        produce: ProduceArgs,
+    },
+    /// Consume until SIGINT/SIGTERM
+    Consume {
+        /// comma separated topics
+        #[arg(long, value_delimiter = ',')]
+        topics: Option<Vec<String>>,
+
+        #[command(flatten)]
+        consume: ConsumeArgs,
+    },
+    /// Produce then consume the same topics until every message is acked, then write a report
+    Bench {
+        /// comma separated topics, used to produce and consume
+        #[arg(long, value_delimiter = ',')]
+        topics: Option<Vec<String>>,
+
+        #[command(flatten)]
+        produce: ProduceArgs,
+
+        #[command(flatten)]
+        consume: ConsumeArgs,
+
+        /// stop consuming after this many seconds even if some messages were not acked
+        #[arg(long)]
+        duration_secs: Option<u64>,
+
+        /// report file, JSON is overwritten and CSV appended
+        #[arg(long)]
+        report: Option<PathBuf>,
+
+        /// json or csv
+        #[arg(long)]
+        report_format: Option<ReportFormat>,
+    },
+    /// Print the effective config and the topics lookup
+    Inspect,
+}
+
+#[derive(Args)]
+struct ProduceArgs {
+    /// messages produced on every topic
+    #[arg(long)]
+    count: Option<usize>,
+
+    /// pulsar producer batch size
+    #[arg(long)]
+    producer_batch_size: Option<u32>,
+
+    /// use the partition keys 0 to N - 1
+    #[arg(long)]
+    keys: Option<usize>,
+
+    /// round_robin, uniform or skewed
+    #[arg(long)]
+    key_distribution: Option<KeyDistribution>,
+}
+
+impl ProduceArgs {
+    fn apply(&self, config: &mut Config) {
+        if let Some(count) = self.count {
+            config.producer.messages_per_topic = count;
+        }
+        if let Some(batch_size) = self.producer_batch_size {
+            config.producer.batch_size = batch_size;
+        }
+        if let Some(keys) = self.keys {
+            config.producer.partition_keys = (0..keys).map(|key| key.to_string()).collect();
+        }
+        if let Some(key_distribution) = self.key_distribution {
+            config.producer.key_distribution = key_distribution;
+        }
+    }
+}
+
+#[derive(Args)]
+struct ConsumeArgs {
+    /// batch or actors
+    #[arg(long)]
+    mode: Option<ConsumeMode>,
+
+    /// maximum handler tasks running at the same time
+    #[arg(long)]
+    max_concurrency: Option<usize>,
+
+    /// batch mode: maximum messages per batch
+    #[arg(long)]
+    batch_size: Option<usize>,
+
+    /// batch mode: maximum time spent filling a batch
+    #[arg(long)]
+    batch_timeout_ms: Option<u64>,
+
+    /// serve prometheus metrics on this address, e.g. 0.0.0.0:9090
+    #[arg(long)]
+    metrics_addr: Option<String>,
+}
+
+impl ConsumeArgs {
+    fn apply(&self, config: &mut Config) {
+        if let Some(mode) = self.mode {
+            config.consumer.mode = mode;
+        }
+        if let Some(max_concurrency) = self.max_concurrency {
+            config.consumer.max_concurrency = max_concurrency;
+        }
+        if let Some(batch_size) = self.batch_size {
+            config.consumer.batch_size = batch_size;
+        }
+        if let Some(batch_timeout_ms) = self.batch_timeout_ms {
+            config.consumer.batch_timeout_ms = batch_timeout_ms;
+        }
+        if let Some(metrics_addr) = &self.metrics_addr {
+            config.metrics.enabled = true;
+            config.metrics.addr = metrics_addr.clone();
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+
+    let mut config = Config::load(cli.config.as_deref())?;
+    if let Some(addr) = cli.pulsar_addr {
+        config.pulsar.addr = addr;
+    }
+    if let Some(level) = cli.log_level {
+        config.log.level = level;
+    }
+    if let Some(format) = cli.log_format {
+        config.log.format = format;
+    }
+    if let Some(exporter) = cli.trace_exporter {
+        config.trace.exporter = exporter;
+    }
+    let telemetry = Telemetry::init(&config.trace)?;
+    logging::init(&config.log, &telemetry)?;
+
+    let result = run(cli.command, config).await;
+    telemetry.shutdown().await;
+    result
+}
+
+async fn run(command: Command, mut config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Produce { topics, produce } => {
+            if let Some(topics) = topics {
+                config.producer.topics = topics;
+            }
+            produce.apply(&mut config);
+
+            commands::produce(&config).await?;
+        }
+        Command::Consume { topics, consume } => {
+            if let Some(topics) = topics {
+                config.consumer.topics.set_names(topics);
+            }
+            consume.apply(&mut config);
+
+            info!("{}", SystemInfo::collect().await);
+            commands::consume(&config, shutdown_on_signal(None)).await?;
+        }
+        Command::Bench {
+            topics,
+            produce,
+            consume,
+            duration_secs,
+            report,
+            report_format,
+        } => {
+            if let Some(topics) = topics {
+                config.producer.topics = topics.clone();
+                config.consumer.topics.set_names(topics);
+            }
+            produce.apply(&mut config);
+            consume.apply(&mut config);
+            if let Some(duration_secs) = duration_secs {
+                config.bench.timeout_secs = duration_secs;
+            }
+            if let Some(report) = report {
+                config.bench.report = report;
+            }
+            if let Some(report_format) = report_format {
+                config.bench.report_format = report_format;
+            }
+
+            let report = commands::bench(&config, shutdown_on_signal(Some(config.bench.timeout()))).await?;
+            let written = report.write(&config.bench.report, config.bench.report_format)?;
+            if written != config.bench.report {
+                warn!(
+                    report = %config.bench.report.display(),
+                    "bench report has other CSV columns, rows written to {}",
+                    written.display()
+                );
+            }
+            info!(report = %written.display(), "bench report written");
+        }
+        Command::Inspect => {
+            commands::inspect(&config).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns a shutdown listener triggered on SIGINT/SIGTERM or once `deadline` elapses
+fn shutdown_on_signal(deadline: Option<Duration>) -> shutdown::Shutdown {
+    let (trigger, shutdown) = shutdown::channel();
+
+    tokio::spawn(async move {
+        let deadline = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            result = shutdown::wait_for_signal() => {
+                if let Err(err) = result {
+                    error!(error = %err, "unable to listen for shutdown signal");
+                    // we also shut down in case of error
+                }
+            }
+            _ = deadline => {}
+        }
+
+        trigger.trigger();
+    });
+
+    shutdown
+}
@@ -0,0 +1,162 @@
+use std::{marker::PhantomData, time::Instant};
+
+use pulsar::{
+    message::{
+        proto::{MessageIdData, MessageMetadata},
+        Payload,
+    },
+    DeserializeMessage,
+};
+use tracing::{info_span, Span};
+
+use crate::telemetry;
+
+/// Property holding the topic the message was first published to, set on retry and dead letters
+pub const REAL_TOPIC: &str = "REAL_TOPIC";
+/// Property holding the original message id, as `ledger:entry:partition:batch_index`
+pub const ORIGIN_MESSAGE_ID: &str = "ORIGIN_MESSAGE_ID";
+/// Property holding how many attempts failed before the message was republished as a retry letter
+pub const RECONSUME_TIMES: &str = "RECONSUMETIMES";
+
+/// Message delivered by a broker subscription, whatever the backend
+///
+/// mirrors `pulsar::consumer::Message` so handlers do not depend on a specific broker.
+/// Every message carries its own tracing span, entered by each actor handling it. The span
+/// continues the trace of the producer when the properties hold a W3C `traceparent`
+pub struct Message<T> {
+    /// origin topic of the message
+    pub topic: String,
+    /// contains the message's data and other metadata
+    pub payload: Payload,
+    pub message_id: MessageIdData,
+    /// number of times this message was delivered before, 0 on the first delivery
+    ///
+    /// the pulsar client does not expose it, the pulsar backend only counts the nacks of the
+    /// consumer, see `PulsarSubscription`
+    pub redelivery_count: u32,
+    span: Span,
+    received_at: Instant,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> Message<T> {
+    pub fn new(topic: String, payload: Payload, message_id: MessageIdData, redelivery_count: u32) -> Self {
+        // not a child of the current span: the message outlives whatever was running when it
+        // was delivered, its parent is the producer span if any
+        let span = info_span!(
+            parent: None,
+            "message",
+            otel.kind = "consumer",
+            topic = %topic,
+            message_id = %format_message_id(&message_id),
+            partition_key = payload.metadata.partition_key.as_deref(),
+            redelivery_count,
+        );
+        telemetry::continue_trace(&span, &payload.metadata.properties);
+
+        Self {
+            topic,
+            payload,
+            message_id,
+            redelivery_count,
+            span,
+            received_at: Instant::now(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Span of the message: topic, message id, partition key and redelivery count
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// When the message was delivered by the subscription, on the local clock
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+
+    /// Pulsar metadata for the message
+    pub fn metadata(&self) -> &MessageMetadata {
+        &self.payload.metadata
+    }
+
+    /// Get message key (partition key)
+    pub fn key(&self) -> Option<&str> {
+        self.payload.metadata.partition_key.as_deref()
+    }
+
+    /// Get a user defined property
+    pub fn property(&self, key: &str) -> Option<&str> {
+        self.payload
+            .metadata
+            .properties
+            .iter()
+            .find(|property| property.key == key)
+            .map(|property| property.value.as_str())
+    }
+
+    /// Topic the message was first published to, before any retry letter
+    pub fn origin_topic(&self) -> &str {
+        self.property(REAL_TOPIC).unwrap_or(&self.topic)
+    }
+
+    /// Id of the message first published, before any retry letter
+    pub fn origin_message_id(&self) -> String {
+        match self.property(ORIGIN_MESSAGE_ID) {
+            Some(message_id) => message_id.to_string(),
+            None => format_message_id(&self.message_id),
+        }
+    }
+
+    /// Current attempt, 1 on the first delivery
+    ///
+    /// counts the redeliveries and the attempts made before the message was republished as
+    /// a retry letter
+    pub fn attempt(&self) -> u32 {
+        let reconsume_times = self
+            .property(RECONSUME_TIMES)
+            .and_then(|times| times.parse::<u32>().ok())
+            .unwrap_or(0);
+
+        reconsume_times + self.redelivery_count + 1
+    }
+}
+
+/// `ledger:entry:partition:batch_index`, -1 for a missing partition or batch index
+fn format_message_id(message_id: &MessageIdData) -> String {
+    format!(
+        "{}:{}:{}:{}",
+        message_id.ledger_id,
+        message_id.entry_id,
+        message_id.partition.unwrap_or(-1),
+        message_id.batch_index.unwrap_or(-1)
+    )
+}
+
+// derive would require `T: Clone`, the clone shares the span and delivery time of the original
+impl<T> Clone for Message<T> {
+    fn clone(&self) -> Self {
+        Self {
+            topic: self.topic.clone(),
+            payload: self.payload.clone(),
+            message_id: self.message_id.clone(),
+            redelivery_count: self.redelivery_count,
+            span: self.span.clone(),
+            received_at: self.received_at,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: DeserializeMessage> Message<T> {
+    /// directly deserialize a message
+    pub fn deserialize(&self) -> T::Output {
+        T::deserialize_message(&self.payload)
+    }
+}
+
+impl<T> From<pulsar::consumer::Message<T>> for Message<T> {
+    fn from(msg: pulsar::consumer::Message<T>) -> Self {
+        Message::new(msg.topic, msg.payload, msg.message_id.id, 0)
+    }
+}
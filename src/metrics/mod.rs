@@ -0,0 +1,241 @@
+use std::{net::SocketAddr, sync::LazyLock, time::Instant};
+
+use axum::{http::header, response::IntoResponse, routing::get, Router};
+use prometheus::{
+    core::Collector, exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tracing::{error, info};
+
+use crate::{
+    error::{Error, Result},
+    handler::HandlerResult,
+    shutdown::Shutdown,
+};
+
+mod runtime;
+
+pub use runtime::{report, ActorMonitors};
+use runtime::RuntimeGauges;
+
+const NAMESPACE: &str = "pulsar_poc";
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Prometheus metrics of both consumer modes
+///
+/// recorded all the time, `serve` exposes them on `/metrics`. Counters are labelled
+/// by topic, gauges add up every executor of the process
+pub struct Metrics {
+    registry: Registry,
+    pub received: IntCounterVec,
+    /// handler succeeded
+    pub processed: IntCounterVec,
+    /// handler returned an error
+    pub failed: IntCounterVec,
+    pub acked: IntCounterVec,
+    pub nacked: IntCounterVec,
+    /// given up on and published to the dead letter topic, labelled by origin topic
+    pub dead_lettered: IntCounterVec,
+    /// given up on and dropped, labelled by origin topic
+    pub discarded: IntCounterVec,
+    pub handler_duration: HistogramVec,
+    /// messages sent by the receivers and not picked up by the executors yet
+    pub executor_queue_depth: IntGauge,
+    /// handler tasks holding a concurrency permit
+    pub permits_in_use: IntGauge,
+    /// permits of every executor, moves at runtime with an adaptive limit
+    pub concurrency_limit: IntGauge,
+    /// handler CPU stages waiting for a slot of the compute pool
+    pub compute_waiting: IntGauge,
+    /// handler CPU stages running on the compute pool
+    pub compute_in_use: IntGauge,
+    /// commands queued to the acker, buffered acks and nacks waiting for their backoff
+    pub acker_backlog: IntGauge,
+    pub ack_flushes: IntCounter,
+    /// time acks waited in the acker buffers
+    pub ack_flush_latency: HistogramVec,
+    /// instrument the actor tasks, sampled by `report`
+    pub monitors: ActorMonitors,
+    runtime: RuntimeGauges,
+}
+
+impl Metrics {
+    /// Metrics of the process, registered on first use
+    pub fn global() -> &'static Metrics {
+        &METRICS
+    }
+
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some(NAMESPACE.to_string()), None)
+            .expect("namespace is a valid metric prefix");
+
+        let per_topic = |name: &str, help: &str| {
+            register(&registry, IntCounterVec::new(Opts::new(name, help), &["topic"]).expect("valid counter"))
+        };
+        let gauge = |name: &str, help: &str| register(&registry, IntGauge::new(name, help).expect("valid gauge"));
+        let histogram = |name: &str, help: &str, labels: &[&str]| {
+            // 1ms to ~16s
+            let buckets = exponential_buckets(0.001, 2.0, 15).expect("valid buckets");
+            let histogram =
+                HistogramVec::new(HistogramOpts::new(name, help).buckets(buckets), labels).expect("valid histogram");
+            register(&registry, histogram)
+        };
+
+        let ack_flushes = register(
+            &registry,
+            IntCounter::new("ack_flushes_total", "Batches of acks flushed by the acker").expect("valid counter"),
+        );
+
+        Self {
+            received: per_topic("messages_received_total", "Messages delivered by the broker"),
+            processed: per_topic("messages_processed_total", "Messages the handler succeeded on"),
+            failed: per_topic("messages_failed_total", "Messages the handler failed on"),
+            acked: per_topic("messages_acked_total", "Messages acked on the broker"),
+            nacked: per_topic("messages_nacked_total", "Messages nacked on the broker"),
+            dead_lettered: per_topic("messages_dead_lettered_total", "Messages given up on and dead lettered"),
+            discarded: per_topic("messages_discarded_total", "Messages given up on and discarded"),
+            handler_duration: histogram("handler_duration_seconds", "Handler latency per message", &["topic"]),
+            executor_queue_depth: gauge("executor_queue_depth", "Messages waiting for an executor"),
+            permits_in_use: gauge("executor_permits_in_use", "Handler tasks running"),
+            concurrency_limit: gauge("executor_concurrency_limit", "Handler tasks the executors may run at the same time"),
+            compute_waiting: gauge("compute_tasks_waiting", "CPU stages waiting for the compute pool"),
+            compute_in_use: gauge("compute_tasks_running", "CPU stages running on the compute pool"),
+            acker_backlog: gauge("acker_backlog", "Acks and nacks waiting in the acker"),
+            ack_flushes,
+            ack_flush_latency: histogram("ack_flush_latency_seconds", "Time acks waited before their flush", &[]),
+            monitors: ActorMonitors::default(),
+            runtime: RuntimeGauges::new(&registry),
+            registry,
+        }
+    }
+
+    /// Records the latency and the outcome of a handler call started at `started`
+    pub fn record_handled(&self, topic: &str, started: Instant, result: &HandlerResult) {
+        self.handler_duration
+            .with_label_values(&[topic])
+            .observe(started.elapsed().as_secs_f64());
+        match result {
+            Ok(()) => self.processed.with_label_values(&[topic]).inc(),
+            Err(_) => self.failed.with_label_values(&[topic]).inc(),
+        }
+    }
+
+    /// Text exposition of every metric
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!(error = %e, "metrics encoding failed");
+        }
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
+
+/// Registers a collector, metric names are constants so it can only fail on a typo
+fn register<C: Collector + Clone + 'static>(registry: &Registry, collector: C) -> C {
+    registry
+        .register(Box::new(collector.clone()))
+        .expect("metric registered once");
+    collector
+}
+
+/// Increments a gauge until dropped, also when the task holding it panics
+pub struct GaugeGuard(&'static IntGauge);
+
+impl GaugeGuard {
+    pub fn inc(gauge: &'static IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Serves the metrics on `http://<addr>/metrics` until shutdown is requested
+pub async fn serve(addr: SocketAddr, mut shutdown: Shutdown) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| Error::Metrics(format!("binding {}: {}", addr, e)))?;
+    info!("serving metrics on http://{}/metrics", addr);
+
+    let app = Router::new().route("/metrics", get(metrics_handler));
+
+    Ok(tokio::spawn(async move {
+        let server = axum::serve(listener, app).with_graceful_shutdown(async move { shutdown.recv().await });
+        if let Err(e) = server.await {
+            error!("{}", Error::Metrics(e.to_string()));
+        }
+    }))
+}
+
+async fn metrics_handler() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, TextEncoder::new().format_type().to_string())],
+        Metrics::global().encode(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::handler::HandlerError;
+
+    use super::*;
+
+    #[test]
+    fn gauge_guard_holds_the_gauge_up_until_dropped() {
+        let gauge: &'static IntGauge = Box::leak(Box::new(IntGauge::new("guarded", "guarded").unwrap()));
+
+        let first = GaugeGuard::inc(gauge);
+        let second = GaugeGuard::inc(gauge);
+        assert_eq!(gauge.get(), 2);
+        drop(first);
+        assert_eq!(gauge.get(), 1);
+        drop(second);
+        assert_eq!(gauge.get(), 0);
+
+        // also released by a panicking task
+        let panicked = thread::spawn(move || {
+            let _guard = GaugeGuard::inc(gauge);
+            panic!("task bug");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert_eq!(gauge.get(), 0);
+    }
+
+    #[test]
+    fn encoding_renders_the_namespaced_metrics_with_their_labels() {
+        // not the global metrics, other tests record on them concurrently
+        let metrics = Metrics::new();
+        metrics.received.with_label_values(&["orders"]).inc();
+        metrics.record_handled("orders", Instant::now(), &Ok(()));
+        metrics.record_handled("orders", Instant::now(), &Err(HandlerError::Retryable("down".to_string())));
+        metrics.dead_lettered.with_label_values(&["orders"]).inc();
+        metrics.permits_in_use.set(3);
+
+        let text = metrics.encode();
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in [
+            "pulsar_poc_messages_received_total{topic=\"orders\"} 1",
+            "pulsar_poc_messages_processed_total{topic=\"orders\"} 1",
+            "pulsar_poc_messages_failed_total{topic=\"orders\"} 1",
+            "pulsar_poc_messages_dead_lettered_total{topic=\"orders\"} 1",
+            "pulsar_poc_handler_duration_seconds_count{topic=\"orders\"} 2",
+            "pulsar_poc_executor_permits_in_use 3",
+            "# TYPE pulsar_poc_handler_duration_seconds histogram",
+            "# TYPE pulsar_poc_executor_queue_depth gauge",
+            "# TYPE pulsar_poc_runtime_workers gauge",
+        ] {
+            assert!(lines.contains(&expected), "{:?} missing from\n{}", expected, text);
+        }
+        // only recorded topics get a series
+        assert!(!text.contains("messages_acked_total{"));
+    }
+}
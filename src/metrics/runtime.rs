@@ -0,0 +1,176 @@
+use std::time::{Duration, Instant};
+
+#[cfg(tokio_unstable)]
+use prometheus::Gauge;
+use prometheus::{GaugeVec, IntGauge, Opts, Registry};
+use tokio::{
+    runtime::Handle,
+    task::JoinHandle,
+    time::{interval, MissedTickBehavior},
+};
+use tokio_metrics::{TaskMetrics, TaskMonitor};
+
+use crate::shutdown::Shutdown;
+
+use super::{register, Metrics};
+
+/// Task monitors of the actors, their futures are instrumented with them
+///
+/// a monitor aggregates every task it instruments, e.g. all the handler tasks
+#[derive(Clone, Default)]
+pub struct ActorMonitors {
+    /// receiver consume loops
+    pub receiver: TaskMonitor,
+    /// handler tasks of the executors, and of the batch handler in batch mode
+    pub executor: TaskMonitor,
+    pub acker: TaskMonitor,
+}
+
+/// Gauges updated by `report`, per actor and for the whole runtime
+///
+/// the worker busy ratio and poll times of the runtime need tokio's unstable metrics,
+/// they are only exported when built with `RUSTFLAGS="--cfg tokio_unstable"`
+pub(super) struct RuntimeGauges {
+    task_mean_poll_duration: GaugeVec,
+    task_mean_scheduled_duration: GaugeVec,
+    task_slow_poll_ratio: GaugeVec,
+    task_busy_ratio: GaugeVec,
+    workers: IntGauge,
+    alive_tasks: IntGauge,
+    global_queue_depth: IntGauge,
+    #[cfg(tokio_unstable)]
+    busy_ratio: Gauge,
+    #[cfg(tokio_unstable)]
+    mean_poll_duration: Gauge,
+    #[cfg(tokio_unstable)]
+    max_worker_mean_poll_duration: Gauge,
+}
+
+impl RuntimeGauges {
+    pub(super) fn new(registry: &Registry) -> Self {
+        let per_actor = |name: &str, help: &str| {
+            register(registry, GaugeVec::new(Opts::new(name, help), &["actor"]).expect("valid gauge"))
+        };
+        let gauge = |name: &str, help: &str| register(registry, IntGauge::new(name, help).expect("valid gauge"));
+        #[cfg(tokio_unstable)]
+        let float_gauge = |name: &str, help: &str| register(registry, Gauge::new(name, help).expect("valid gauge"));
+
+        Self {
+            task_mean_poll_duration: per_actor("task_mean_poll_duration_seconds", "Mean time of a poll of the actor tasks"),
+            task_mean_scheduled_duration: per_actor(
+                "task_mean_scheduled_duration_seconds",
+                "Mean time the actor tasks waited for a worker once woken up",
+            ),
+            task_slow_poll_ratio: per_actor("task_slow_poll_ratio", "Share of the actor task polls slower than 50us"),
+            task_busy_ratio: per_actor(
+                "task_busy_ratio",
+                "Time spent polling the actor tasks over wall time, above 1 when they run on several workers",
+            ),
+            workers: gauge("runtime_workers", "Tokio worker threads"),
+            alive_tasks: gauge("runtime_alive_tasks", "Tasks alive in the runtime"),
+            global_queue_depth: gauge("runtime_global_queue_depth", "Tasks waiting in the runtime global queue"),
+            #[cfg(tokio_unstable)]
+            busy_ratio: float_gauge("runtime_busy_ratio", "Share of the time the workers were busy"),
+            #[cfg(tokio_unstable)]
+            mean_poll_duration: float_gauge("runtime_mean_poll_duration_seconds", "Mean time of a task poll"),
+            #[cfg(tokio_unstable)]
+            max_worker_mean_poll_duration: float_gauge(
+                "runtime_max_worker_mean_poll_duration_seconds",
+                "Mean time of a task poll on the slowest worker",
+            ),
+        }
+    }
+
+    fn record_task(&self, actor: &str, metrics: &TaskMetrics, elapsed: Duration) {
+        self.task_mean_poll_duration
+            .with_label_values(&[actor])
+            .set(metrics.mean_poll_duration().as_secs_f64());
+        self.task_mean_scheduled_duration
+            .with_label_values(&[actor])
+            .set(metrics.mean_scheduled_duration().as_secs_f64());
+        self.task_slow_poll_ratio
+            .with_label_values(&[actor])
+            .set(ratio(metrics.slow_poll_ratio()));
+        self.task_busy_ratio
+            .with_label_values(&[actor])
+            .set(ratio(metrics.total_poll_duration.as_secs_f64() / elapsed.as_secs_f64()));
+    }
+
+    fn record_runtime(&self, runtime: &Handle) {
+        let metrics = runtime.metrics();
+        self.workers.set(metrics.num_workers() as i64);
+        self.alive_tasks.set(metrics.num_alive_tasks() as i64);
+        self.global_queue_depth.set(metrics.global_queue_depth() as i64);
+    }
+
+    #[cfg(tokio_unstable)]
+    fn record_unstable(&self, metrics: &tokio_metrics::RuntimeMetrics) {
+        self.busy_ratio.set(ratio(metrics.busy_ratio()));
+        self.mean_poll_duration.set(metrics.mean_poll_duration.as_secs_f64());
+        self.max_worker_mean_poll_duration
+            .set(metrics.mean_poll_duration_worker_max.as_secs_f64());
+    }
+}
+
+/// 0 instead of NaN when nothing happened during the interval
+fn ratio(value: f64) -> f64 {
+    if value.is_finite() {
+        value
+    } else {
+        0.0
+    }
+}
+
+/// Samples the actor task monitors and the runtime every `every` until shutdown is requested
+///
+/// each sample covers the time since the previous one, a receiver or executor starved by
+/// blocking handlers shows up as a growing scheduled duration and a busy ratio close to 1
+pub fn report(every: Duration, mut shutdown: Shutdown) -> JoinHandle<()> {
+    let metrics = Metrics::global();
+    let runtime = Handle::current();
+
+    let mut actors: Vec<_> = [
+        ("receiver", &metrics.monitors.receiver),
+        ("executor", &metrics.monitors.executor),
+        ("acker", &metrics.monitors.acker),
+    ]
+    .into_iter()
+    .map(|(actor, monitor)| (actor, monitor.intervals()))
+    .collect();
+    #[cfg(tokio_unstable)]
+    let mut runtime_intervals = tokio_metrics::RuntimeMonitor::new(&runtime).intervals();
+
+    tokio::spawn(async move {
+        let mut ticker = interval(every);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // the first tick completes right away
+        ticker.tick().await;
+        // the first interval covers everything since the monitors were created, skip it
+        for (_, intervals) in &mut actors {
+            intervals.next();
+        }
+        #[cfg(tokio_unstable)]
+        runtime_intervals.next();
+        let mut sampled_at = Instant::now();
+
+        loop {
+            tokio::select! {
+                _ = shutdown.recv() => break,
+                _ = ticker.tick() => {}
+            }
+            let elapsed = sampled_at.elapsed();
+            sampled_at = Instant::now();
+
+            for (actor, intervals) in &mut actors {
+                if let Some(task_metrics) = intervals.next() {
+                    metrics.runtime.record_task(actor, &task_metrics, elapsed);
+                }
+            }
+            metrics.runtime.record_runtime(&runtime);
+            #[cfg(tokio_unstable)]
+            if let Some(runtime_metrics) = runtime_intervals.next() {
+                metrics.runtime.record_unstable(&runtime_metrics);
+            }
+        }
+    })
+}
@@ -0,0 +1,43 @@
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    broker::Broker,
+    dead_letter::letter_message,
+    error::Result,
+    handler::HandlerError,
+    message::{Message, RECONSUME_TIMES},
+};
+
+/// Retry topic of `topic`, messages retried from it keep going to the same retry topic
+pub fn retry_topic(topic: &str) -> String {
+    format!("{}-RETRY", topic)
+}
+
+/// Republishes retryable messages to `<topic>-RETRY`, delivered once their backoff elapsed
+///
+/// unlike a delayed nack the original can be acked right away, so a slow retry does not
+/// hold unacked state in the main subscription. `RECONSUME_TIMES` carries the attempts
+/// count over, so the retry policy still applies to the republished message
+#[derive(Clone)]
+pub struct RetryLetter<B> {
+    broker: B,
+}
+
+impl<B: Broker> RetryLetter<B> {
+    pub fn new(broker: B) -> Self {
+        Self { broker }
+    }
+
+    pub fn topic_for(&self, origin_topic: &str) -> String {
+        retry_topic(origin_topic)
+    }
+
+    /// Publishes `msg` again for its next attempt, after `attempt` failed with `error`
+    pub async fn publish<T>(&self, msg: &Message<T>, error: &HandlerError, attempt: u32, delay: Duration) -> Result<()> {
+        let message = letter_message(msg, error, [(RECONSUME_TIMES, attempt.to_string())]);
+
+        self.broker
+            .produce_at(&self.topic_for(msg.origin_topic()), message, SystemTime::now() + delay)
+            .await
+    }
+}
@@ -0,0 +1,57 @@
+use tokio::{signal, sync::watch};
+
+/// Creates a shutdown trigger and the listener handed out to every actor
+pub fn channel() -> (ShutdownTrigger, Shutdown) {
+    let (tx, rx) = watch::channel(false);
+    (ShutdownTrigger { tx }, Shutdown { rx })
+}
+
+/// Requests every `Shutdown` listener to stop
+///
+/// dropping the trigger without calling `trigger` also stops the listeners
+pub struct ShutdownTrigger {
+    tx: watch::Sender<bool>,
+}
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+}
+
+/// Cloneable listener for the pipeline shutdown request
+#[derive(Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Completes once shutdown was requested
+    pub async fn recv(&mut self) {
+        // an error means the trigger is gone, which is treated as a shutdown request
+        let _ = self.rx.wait_for(|stop| *stop).await;
+    }
+
+    /// Whether shutdown was requested, without waiting
+    pub fn is_requested(&self) -> bool {
+        // same as `recv`, a dropped trigger counts as a request
+        *self.rx.borrow() || self.rx.has_changed().is_err()
+    }
+}
+
+/// Waits for SIGINT (ctrl_c) or, on unix, SIGTERM so the pipeline also stops cleanly in containers
+pub async fn wait_for_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = signal::ctrl_c() => result,
+            _ = sigterm.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        signal::ctrl_c().await
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+
+use sysinfo::System;
+use tokio::{process::Command, runtime::Handle};
+
+/// Host and runtime details printed before benchmarking, and written in the bench report
+#[derive(Debug, Clone, Serialize)]
+pub struct SystemInfo {
+    pub os: String,
+    pub kernel_version: String,
+    pub rust_version: String,
+    pub total_memory_gb: u64,
+    pub total_swap_gb: u64,
+    pub cpu_brand: String,
+    pub cpus: usize,
+    pub tokio_workers: usize,
+}
+
+impl SystemInfo {
+    /// Collects the system info, must run inside a tokio runtime
+    pub async fn collect() -> Self {
+        // Get system info
+        let sys = System::new_all();
+
+        // tokio runtime metrics
+        let metrics = Handle::current().metrics();
+
+        // Get Rust version safely
+        let rust_version = match Command::new("rustc").arg("--version").output().await {
+            Ok(output) => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            Err(e) => format!("unknown ({})", e),
+        };
+
+        Self {
+            os: System::long_os_version().unwrap_or_default(),
+            kernel_version: System::kernel_version().unwrap_or_default(),
+            rust_version,
+            total_memory_gb: sys.total_memory() / (1024 * 1024 * 1024),
+            total_swap_gb: sys.total_swap() / (1024 * 1024 * 1024),
+            cpu_brand: sys
+                .cpus()
+                .first()
+                .map(|cpu| cpu.brand().to_string())
+                .unwrap_or_default(),
+            cpus: sys.cpus().len(),
+            tokio_workers: metrics.num_workers(),
+        }
+    }
+}
+
+impl fmt::Display for SystemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "=== System Info ===:
+    OS: {:?}
+    Kernel Version: {:?}
+    Rust Version: {}
+    Total Memory: {} GB
+    Total Swap: {} GB
+    CPU Brand: {:?}
+    CPUs: {:?}
+    Tokio Executor Threads: {:?}
+=== System Info ===",
+            self.os,
+            self.kernel_version,
+            self.rust_version,
+            self.total_memory_gb,
+            self.total_swap_gb,
+            self.cpu_brand,
+            self.cpus,
+            self.tokio_workers,
+        )
+    }
+}
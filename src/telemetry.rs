@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::future::BoxFuture;
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::{TraceError, TracerProvider as _},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    export::trace::{ExportResult, SpanData, SpanExporter},
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{Tracer, TracerProvider},
+    Resource,
+};
+use pulsar::message::proto::KeyValue as Property;
+use tracing::{error, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::{
+    config::TraceConfig,
+    error::{Error, Result},
+};
+
+/// Where the spans are exported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceExporter {
+    /// spans stay local to the logs, the trace context is still propagated
+    None,
+    /// OTLP over gRPC to a collector, e.g. `http://127.0.0.1:4317`
+    Otlp,
+    /// one JSON object per span appended to a local file, for tests
+    File,
+}
+
+impl FromStr for TraceExporter {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(TraceExporter::None),
+            "otlp" => Ok(TraceExporter::Otlp),
+            "file" => Ok(TraceExporter::File),
+            _ => Err(format!("unknown trace exporter {:?}, expected none, otlp or file", s)),
+        }
+    }
+}
+
+/// Tracer provider of the process, flushes the pending spans on shutdown
+pub struct Telemetry {
+    provider: Option<TracerProvider>,
+}
+
+impl Telemetry {
+    /// Installs the W3C trace context propagator and builds the configured exporter
+    ///
+    /// must run inside the tokio runtime, spans are exported in batches by a background task
+    pub fn init(config: &TraceConfig) -> Result<Self> {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let provider = TracerProvider::builder().with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            config.service_name.clone(),
+        )]));
+
+        let provider = match config.exporter {
+            TraceExporter::None => return Ok(Self { provider: None }),
+            TraceExporter::Otlp => {
+                let exporter = opentelemetry_otlp::SpanExporter::builder()
+                    .with_tonic()
+                    .with_endpoint(config.otlp_endpoint.clone())
+                    .build()
+                    .map_err(|e| Error::Telemetry(format!("otlp exporter: {}", e)))?;
+                provider.with_batch_exporter(exporter, runtime::Tokio)
+            }
+            TraceExporter::File => {
+                let exporter = FileExporter::create(config.file.clone())?;
+                provider.with_batch_exporter(exporter, runtime::Tokio)
+            }
+        };
+
+        Ok(Self {
+            provider: Some(provider.build()),
+        })
+    }
+
+    /// Tracer feeding the exporter, `None` when spans are not exported
+    pub fn tracer(&self) -> Option<Tracer> {
+        self.provider.as_ref().map(|provider| provider.tracer(env!("CARGO_PKG_NAME")))
+    }
+
+    /// Exports the spans still buffered then stops the exporter
+    pub async fn shutdown(self) {
+        let Some(provider) = self.provider else {
+            return;
+        };
+
+        // shutting down blocks until the batch task, running on the runtime, is done
+        match tokio::task::spawn_blocking(move || provider.shutdown()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!(error = %e, "trace exporter shutdown failed"),
+            Err(e) => error!(error = ?e, "trace exporter shutdown task failed"),
+        }
+    }
+}
+
+/// Writes `traceparent` and `tracestate` of the current span into the message properties
+///
+/// nothing is written unless the spans are exported
+pub fn inject(properties: &mut HashMap<String, String>) {
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut PropertiesInjector(properties))
+    });
+}
+
+/// Makes `span` a child of the trace context found in the message properties, if any
+pub fn continue_trace(span: &Span, properties: &[Property]) {
+    if !properties.iter().any(|property| property.key == "traceparent") {
+        return;
+    }
+
+    let context: Context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&PropertiesExtractor(properties)));
+    span.set_parent(context);
+}
+
+struct PropertiesInjector<'a>(&'a mut HashMap<String, String>);
+
+impl Injector for PropertiesInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.insert(key.to_string(), value);
+    }
+}
+
+struct PropertiesExtractor<'a>(&'a [Property]);
+
+impl Extractor for PropertiesExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|property| property.key == key)
+            .map(|property| property.value.as_str())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|property| property.key.as_str()).collect()
+    }
+}
+
+/// Appends every span as a JSON line: ids, name, timestamps and attributes
+#[derive(Debug)]
+struct FileExporter {
+    writer: BufWriter<File>,
+}
+
+impl FileExporter {
+    fn create(path: PathBuf) -> Result<Self> {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| Error::Telemetry(format!("opening {}: {}", path.display(), e)))?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    fn write(&mut self, span: &SpanData) -> std::io::Result<()> {
+        let attributes: serde_json::Map<String, serde_json::Value> = span
+            .attributes
+            .iter()
+            .map(|attribute| (attribute.key.to_string(), attribute.value.to_string().into()))
+            .collect();
+
+        let line = serde_json::json!({
+            "trace_id": span.span_context.trace_id().to_string(),
+            "span_id": span.span_context.span_id().to_string(),
+            "parent_span_id": span.parent_span_id.to_string(),
+            "name": span.name,
+            "start_time_unix_nano": unix_nanos(span.start_time),
+            "end_time_unix_nano": unix_nanos(span.end_time),
+            "attributes": attributes,
+        });
+
+        serde_json::to_writer(&mut self.writer, &line)?;
+        self.writer.write_all(b"\n")
+    }
+}
+
+impl SpanExporter for FileExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let result = batch
+            .iter()
+            .try_for_each(|span| self.write(span))
+            .and_then(|()| self.writer.flush())
+            .map_err(|e| TraceError::from(format!("writing spans: {}", e)));
+
+        Box::pin(std::future::ready(result))
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use pulsar::SerializeMessage;
+    use tracing::info_span;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use crate::TestData;
+
+    use super::*;
+
+    fn span_named<'a>(spans: &'a [serde_json::Value], name: &str) -> &'a serde_json::Value {
+        spans.iter().find(|span| span["name"] == name).unwrap()
+    }
+
+    #[test]
+    fn consumer_span_continues_the_producer_trace() {
+        let path = std::env::temp_dir().join(format!("poc-traces-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(FileExporter::create(path.clone()).unwrap())
+            .build();
+        let subscriber =
+            Registry::default().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let message = info_span!("publish").in_scope(|| {
+                TestData::serialize_message(TestData {
+                    data: "data".to_string(),
+                    partition_key: "key".to_string(),
+                })
+                .unwrap()
+            });
+            assert!(message.properties.contains_key("traceparent"));
+
+            let properties: Vec<Property> = message
+                .properties
+                .into_iter()
+                .map(|(key, value)| Property { key, value })
+                .collect();
+            let span = info_span!("receive");
+            continue_trace(&span, &properties);
+        });
+        provider.shutdown().unwrap();
+
+        let spans: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        fs::remove_file(&path).unwrap();
+
+        let publish = span_named(&spans, "publish");
+        let receive = span_named(&spans, "receive");
+        assert_eq!(receive["trace_id"], publish["trace_id"]);
+        assert_eq!(receive["parent_span_id"], publish["span_id"]);
+    }
+}
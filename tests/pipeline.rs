@@ -0,0 +1,373 @@
+//! Actors pipeline end to end over the in-memory broker
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use pulsar::{SerializeMessage, SubType};
+use tokio::{task::JoinHandle, time::sleep};
+
+use pulsar_rust_poc::{
+    actors::{AckerHandle, ExecutorHandle, PipelineConfig, Receiver, RetryMode, RetryPolicy},
+    broker::{Broker, MemoryBroker, MemorySubscription, SubscribeOptions, Subscription},
+    handler::{Handler, HandlerError, HandlerResult},
+    message::Message,
+    shutdown::{self, ShutdownTrigger},
+    TestData,
+};
+
+const TOPIC: &str = "pipeline";
+
+/// Handler recording every message it handled
+///
+/// attempts up to `failures` fail with a retryable error, every attempt takes `delay`
+#[derive(Clone, Default)]
+struct Recorder {
+    failures: u32,
+    delay: Duration,
+    started: Arc<AtomicUsize>,
+    handled: Arc<Mutex<Vec<Handled>>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Handled {
+    data: String,
+    key: String,
+    attempt: u32,
+}
+
+impl Handler<TestData> for Recorder {
+    async fn handle(&self, msg: &Message<TestData>) -> HandlerResult {
+        self.started.fetch_add(1, Ordering::SeqCst);
+        sleep(self.delay).await;
+
+        let data = msg
+            .deserialize()
+            .map_err(|e| HandlerError::Permanent(e.to_string()))?;
+        let attempt = msg.attempt();
+        self.handled.lock().unwrap().push(Handled {
+            data: data.data,
+            key: data.partition_key,
+            attempt,
+        });
+
+        if attempt <= self.failures {
+            return Err(HandlerError::Retryable(format!("attempt {} fails", attempt)));
+        }
+        Ok(())
+    }
+}
+
+impl Recorder {
+    fn handled(&self) -> Vec<Handled> {
+        self.handled.lock().unwrap().clone()
+    }
+
+    /// Attempts seen per message data
+    fn attempts(&self) -> BTreeMap<String, Vec<u32>> {
+        let mut attempts: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+        for handled in self.handled() {
+            attempts.entry(handled.data).or_default().push(handled.attempt);
+        }
+        attempts
+    }
+}
+
+/// Receivers, executor and acker of one consumer, wired like `consume_actors`
+struct Pipeline {
+    trigger: ShutdownTrigger,
+    receivers: Vec<JoinHandle<Receiver<TestData, MemorySubscription<TestData>>>>,
+    executor: ExecutorHandle<TestData>,
+    acker: AckerHandle<TestData>,
+}
+
+impl Pipeline {
+    async fn start(broker: &MemoryBroker, config: &PipelineConfig, handler: Recorder) -> Self {
+        let (trigger, shutdown) = shutdown::channel();
+        let acker = AckerHandle::<TestData>::new(config, broker.clone()).await;
+        let topics = [TOPIC.to_string()];
+        let executor = ExecutorHandle::new(handler, &topics, acker.acker_tx.clone(), config, shutdown.clone()).await;
+
+        let mut receivers = Vec::new();
+        for (topic, executor_tx) in executor.senders() {
+            let receiver = Receiver::new(broker, topic.clone(), config, executor_tx.clone()).await.unwrap();
+            let retry_receiver = Receiver::retry(broker, topic, config, executor_tx.clone()).await.unwrap();
+
+            for mut receiver in std::iter::once(receiver).chain(retry_receiver) {
+                let shutdown = shutdown.clone();
+                receivers.push(tokio::spawn(async move {
+                    receiver.consume(shutdown).await.unwrap();
+                    receiver
+                }));
+            }
+        }
+
+        Self {
+            trigger,
+            receivers,
+            executor,
+            acker,
+        }
+    }
+
+    /// Shuts down in the order of `consume_actors`: receivers, executor drain, acker flush,
+    /// then the receivers apply the last acks and close their consumer
+    async fn stop(self) {
+        self.trigger.trigger();
+
+        let mut receivers = Vec::new();
+        for receiver in self.receivers {
+            receivers.push(receiver.await.unwrap());
+        }
+        self.executor.wait().await;
+        self.acker.wait().await;
+        for receiver in receivers {
+            receiver.close().await.unwrap();
+        }
+    }
+}
+
+fn config() -> PipelineConfig {
+    PipelineConfig {
+        subscription_type: SubType::Shared,
+        unacked_resend_delay: Some(Duration::from_secs(5)),
+        retry: RetryPolicy {
+            mode: RetryMode::Nack,
+            max_attempts: 5,
+            base_delay: Duration::from_millis(20),
+            multiplier: 2.0,
+            max_delay: Duration::from_millis(100),
+            jitter: 0.0,
+            ..Default::default()
+        },
+        ack_flush_interval: Duration::from_millis(10),
+        ..Default::default()
+    }
+}
+
+async fn produce(broker: &MemoryBroker, messages: usize, keys: usize) {
+    for i in 0..messages {
+        let data = TestData {
+            data: format!("message-{}", i),
+            partition_key: format!("key-{}", i % keys),
+        };
+        broker
+            .produce(TOPIC, TestData::serialize_message(data).unwrap())
+            .await
+            .unwrap();
+    }
+}
+
+/// Waits up to 5s for `done`
+async fn eventually(mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done() {
+        assert!(Instant::now() < deadline, "condition not met within 5s");
+        sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// `next` of a subscription, `None` if nothing was delivered within `within`
+async fn next_within<F>(next: F, within: Duration) -> Option<Message<TestData>>
+where
+    F: Future<Output = pulsar_rust_poc::error::Result<Option<Message<TestData>>>>,
+{
+    tokio::time::timeout(within, next).await.ok().map(|msg| msg.unwrap().unwrap())
+}
+
+#[tokio::test]
+async fn handled_messages_are_acked() {
+    let broker = MemoryBroker::new();
+    let recorder = Recorder::default();
+    let pipeline = Pipeline::start(&broker, &config(), recorder.clone()).await;
+
+    produce(&broker, 20, 4).await;
+    eventually(|| recorder.handled().len() == 20).await;
+    pipeline.stop().await;
+
+    let attempts = recorder.attempts();
+    assert_eq!(attempts.len(), 20);
+    assert!(attempts.values().all(|attempts| attempts == &[1]));
+    assert_eq!(broker.pending(TOPIC, &config().subscription), 0);
+}
+
+#[tokio::test]
+async fn nacked_messages_are_redelivered_with_the_next_attempt() {
+    let broker = MemoryBroker::new();
+    let recorder = Recorder {
+        failures: 2,
+        ..Default::default()
+    };
+    let pipeline = Pipeline::start(&broker, &config(), recorder.clone()).await;
+
+    produce(&broker, 5, 5).await;
+    eventually(|| recorder.handled().len() == 15).await;
+    pipeline.stop().await;
+
+    let attempts = recorder.attempts();
+    assert_eq!(attempts.len(), 5);
+    assert!(attempts.values().all(|attempts| attempts == &[1, 2, 3]));
+    assert_eq!(broker.pending(TOPIC, &config().subscription), 0);
+}
+
+#[tokio::test]
+async fn retry_letters_come_back_after_their_backoff() {
+    let broker = MemoryBroker::new();
+    let recorder = Recorder {
+        failures: 1,
+        ..Default::default()
+    };
+    let mut config = config();
+    config.retry.mode = RetryMode::RetryLetter;
+    config.retry.base_delay = Duration::from_millis(200);
+    config.retry.max_delay = Duration::from_millis(200);
+    let pipeline = Pipeline::start(&broker, &config, recorder.clone()).await;
+
+    let started = Instant::now();
+    produce(&broker, 3, 3).await;
+    eventually(|| recorder.handled().len() == 3).await;
+    sleep(Duration::from_millis(100)).await;
+    // still delayed
+    assert_eq!(recorder.handled().len(), 3);
+
+    eventually(|| recorder.handled().len() == 6).await;
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    pipeline.stop().await;
+
+    assert!(recorder.attempts().values().all(|attempts| attempts == &[1, 2]));
+    assert_eq!(broker.pending(TOPIC, &config.subscription), 0);
+}
+
+#[tokio::test]
+async fn key_shared_keeps_every_key_on_one_consumer() {
+    let broker = MemoryBroker::new();
+    let mut pipelines = Vec::new();
+    let mut recorders = Vec::new();
+    for consumer in ["first", "second"] {
+        let config = PipelineConfig {
+            subscription_type: SubType::KeyShared,
+            consumer_name: consumer.to_string(),
+            ..config()
+        };
+        let recorder = Recorder::default();
+        pipelines.push(Pipeline::start(&broker, &config, recorder.clone()).await);
+        recorders.push(recorder);
+    }
+
+    produce(&broker, 64, 8).await;
+    eventually(|| recorders.iter().map(|recorder| recorder.handled().len()).sum::<usize>() == 64).await;
+    for pipeline in pipelines {
+        pipeline.stop().await;
+    }
+
+    let keys: Vec<BTreeSet<String>> = recorders
+        .iter()
+        .map(|recorder| recorder.handled().into_iter().map(|handled| handled.key).collect())
+        .collect();
+    assert!(keys.iter().all(|keys| !keys.is_empty()), "every consumer gets keys: {:?}", keys);
+    assert!(keys[0].is_disjoint(&keys[1]), "a key went to both consumers: {:?}", keys);
+    assert_eq!(keys[0].len() + keys[1].len(), 8);
+}
+
+#[tokio::test]
+async fn unacked_messages_are_resent_after_the_delay() {
+    let broker = MemoryBroker::new();
+    let options = SubscribeOptions {
+        unacked_resend_delay: Some(Duration::from_millis(300)),
+        ..config().subscribe_options(vec![TOPIC.to_string()])
+    };
+    let mut subscription = broker.subscribe::<TestData>(&options).await.unwrap();
+
+    produce(&broker, 1, 1).await;
+    let delivered = Instant::now();
+    let msg = next_within(subscription.next(), Duration::from_secs(1)).await.unwrap();
+    assert_eq!(msg.redelivery_count, 0);
+
+    // neither acked nor nacked
+    assert!(next_within(subscription.next(), Duration::from_millis(150)).await.is_none());
+    let resent = next_within(subscription.next(), Duration::from_secs(2)).await.unwrap();
+    assert!(delivered.elapsed() >= Duration::from_millis(300));
+    assert_eq!(resent.message_id, msg.message_id);
+    assert_eq!(resent.redelivery_count, 1);
+    assert_eq!(resent.attempt(), 2);
+
+    subscription.ack(TOPIC, resent.message_id).await.unwrap();
+    assert!(next_within(subscription.next(), Duration::from_millis(600)).await.is_none());
+    assert_eq!(broker.pending(TOPIC, &options.subscription), 0);
+}
+
+#[tokio::test]
+async fn produce_at_delays_shared_subscriptions_only() {
+    let broker = MemoryBroker::new();
+    let shared = config().subscribe_options(vec![TOPIC.to_string()]);
+    let key_shared = SubscribeOptions {
+        subscription: "key_shared".to_string(),
+        subscription_type: SubType::KeyShared,
+        ..shared.clone()
+    };
+    let mut shared = broker.subscribe::<TestData>(&shared).await.unwrap();
+    let mut key_shared = broker.subscribe::<TestData>(&key_shared).await.unwrap();
+
+    let data = TestData {
+        data: "delayed".to_string(),
+        partition_key: "key".to_string(),
+    };
+    let produced = Instant::now();
+    let deliver_at = SystemTime::now() + Duration::from_millis(300);
+    broker
+        .produce_at(TOPIC, TestData::serialize_message(data).unwrap(), deliver_at)
+        .await
+        .unwrap();
+
+    assert!(next_within(key_shared.next(), Duration::from_millis(100)).await.is_some());
+    assert!(next_within(shared.next(), Duration::from_millis(150)).await.is_none());
+    assert!(next_within(shared.next(), Duration::from_secs(2)).await.is_some());
+    assert!(produced.elapsed() >= Duration::from_millis(300));
+}
+
+#[tokio::test]
+async fn shutdown_drains_the_tasks_in_flight() {
+    let broker = MemoryBroker::new();
+    let recorder = Recorder {
+        delay: Duration::from_millis(300),
+        ..Default::default()
+    };
+    let pipeline = Pipeline::start(&broker, &config(), recorder.clone()).await;
+
+    produce(&broker, 5, 5).await;
+    eventually(|| recorder.started.load(Ordering::SeqCst) == 5).await;
+    assert!(recorder.handled().is_empty());
+    pipeline.stop().await;
+
+    // every task finished and its ack was applied before the consumer closed
+    assert_eq!(recorder.handled().len(), 5);
+    assert_eq!(broker.pending(TOPIC, &config().subscription), 0);
+}
+
+#[tokio::test]
+async fn shutdown_leaves_the_tasks_past_the_drain_timeout_to_the_broker() {
+    let broker = MemoryBroker::new();
+    let recorder = Recorder {
+        delay: Duration::from_secs(10),
+        ..Default::default()
+    };
+    let config = PipelineConfig {
+        drain_timeout: Duration::from_millis(50),
+        ..config()
+    };
+    let pipeline = Pipeline::start(&broker, &config, recorder.clone()).await;
+
+    produce(&broker, 5, 5).await;
+    eventually(|| recorder.started.load(Ordering::SeqCst) == 5).await;
+    pipeline.stop().await;
+
+    // aborted, they wait for the next consumer
+    assert!(recorder.handled().is_empty());
+    assert_eq!(broker.pending(TOPIC, &config.subscription), 5);
+}